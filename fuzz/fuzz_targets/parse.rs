@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// ircrab is a binary, so the parser is compiled in directly rather than linked
+#[allow(dead_code)]
+#[path = "../../src/encoding.rs"]
+mod encoding;
+#[allow(dead_code)]
+#[path = "../../src/irc.rs"]
+mod irc;
+
+fuzz_target!(|data: &[u8]| {
+    // the parser only ever sees lines, decoded the same way the bot does
+    let (line, _) = encoding::decode(data, encoding::Charset::Cp1252);
+    let line = line.split('\n').next().unwrap_or("");
+    if let Ok(msg) = irc::MessageRef::parse(line) {
+        let _ = irc::Message::from(msg);
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/encoding.rs"]
+mod encoding;
+#[allow(dead_code)]
+#[path = "../../src/irc.rs"]
+mod irc;
+
+// anything the parser accepts and the serializer can represent has to survive the round trip
+fuzz_target!(|data: &[u8]| {
+    let (line, _) = encoding::decode(data, encoding::Charset::Cp1252);
+    irc::check_round_trip(line.split('\n').next().unwrap_or(""));
+});
@@ -0,0 +1,60 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Capped exponential backoff between reconnect attempts
+#[derive(PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// The delay before the given reconnect attempt, starting from 1.
+    /// Half of the delay is fixed and half is random, so that a crowd of clients dropped by
+    /// the same netsplit doesn't reconnect in lockstep.
+    pub fn delay(&self, attempt: u32, random: u64) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let capped = self.initial.saturating_mul(factor).min(self.max);
+        let half = capped.as_millis() as u64 / 2;
+        Duration::from_millis(half + random % (half + 1))
+    }
+}
+
+/// A random number from the stdlib's hasher seeding, which is plenty for jitter
+pub fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_exponentially_up_to_the_cap() {
+        let b = Backoff {
+            initial: Duration::from_secs(2),
+            max: Duration::from_secs(60),
+        };
+        // with no jitter, the delay is exactly half of the backoff
+        assert_eq!(b.delay(1, 0), Duration::from_secs(1));
+        assert_eq!(b.delay(2, 0), Duration::from_secs(2));
+        assert_eq!(b.delay(3, 0), Duration::from_secs(4));
+        assert_eq!(b.delay(6, 0), Duration::from_secs(30));
+        assert_eq!(b.delay(7, 0), Duration::from_secs(30));
+        assert_eq!(b.delay(u32::MAX, 0), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn jitters_within_bounds() {
+        let b = Backoff {
+            initial: Duration::from_secs(2),
+            max: Duration::from_secs(60),
+        };
+        assert_eq!(b.delay(3, 4000), Duration::from_secs(8));
+        for _ in 0..100 {
+            let d = b.delay(3, random());
+            assert!(d >= Duration::from_secs(4) && d <= Duration::from_secs(8));
+        }
+    }
+}
@@ -1,112 +1,1076 @@
+use crate::casemap::{CaseMapping, Folded};
+use crate::queue::{Priority, Receiver, Sender};
+use crate::state::State;
+use crate::triggers::on_connect;
 use crate::triggers::Trigger;
-use crate::{irc, triggers, Config};
-use mpsc::{Receiver, Sender};
-use std::io::{BufRead, BufReader, LineWriter, Write};
-use std::net::TcpStream;
-use std::sync::mpsc;
-use std::thread;
+use crate::{
+    backoff, cap, config, delivery, encoding, irc, keepalive, nick, queue, reload, sasl, secret,
+    throttle, tls, triggers, Channel, Config, Network,
+};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, ErrorKind, LineWriter, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fmt, thread};
+use zeroize::Zeroize;
+
+// how often to check whether a reload was asked for while the connection is quiet
+const RELOAD_POLL: Duration = Duration::from_secs(1);
 
 pub struct Bot {
     cfg: Config,
-    tx: Sender<irc::Message>,
-    rx: Receiver<irc::Message>,
+    status: Arc<RwLock<Status>>,
+    // the channels we're in, so they can be rejoined after reconnecting,
+    // keyed by the server's casemapping
+    channels: BTreeMap<Folded, String>,
+    // what's known of the current connection
+    state: State,
+    // where to reload the config from, if anywhere
+    reload: Option<reload::Source>,
+    // the one message to send, if that's all the bot connects for
+    delivery: Option<delivery::Delivery>,
+}
+
+/// The health of the bot's connection, as seen from outside the bot
+#[derive(Clone, Default)]
+pub struct Status {
+    pub connected: bool,
+    // reconnect attempts since the bot last finished registering
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "connected: {}, reconnect attempts: {}, last error: {}",
+            self.connected,
+            self.attempts,
+            self.last_error.as_deref().unwrap_or("none")
+        )
+    }
 }
 
+// Why a connection ended
+enum Disconnect {
+    // the connection was lost, and is worth retrying
+    Lost(String),
+    // retrying won't help, e.g. the credentials were rejected
+    Fatal(String),
+    // we quit, having done what we connected for
+    Done,
+}
+
+impl From<std::io::Error> for Disconnect {
+    fn from(e: std::io::Error) -> Self {
+        Disconnect::Lost(e.to_string())
+    }
+}
+
+// the halves of a connection for the reader and writer, and the socket underneath them
+type Transport = (Box<dyn Read + Send>, Box<dyn Write + Send>, TcpStream);
+
 pub(crate) fn new(cfg: Config) -> Bot {
-    let (tx, rx): (Sender<irc::Message>, Receiver<irc::Message>) = mpsc::channel();
-    Bot { cfg, tx, rx }
+    Bot {
+        state: State::new(&cfg.my_nick),
+        cfg,
+        status: Arc::new(RwLock::new(Status::default())),
+        channels: BTreeMap::new(),
+        reload: None,
+        delivery: None,
+    }
 }
 
 impl Bot {
-    pub fn run(self) -> std::io::Result<()> {
-        let stream = TcpStream::connect((self.cfg.network.host, self.cfg.network.port))?;
-        let mut writer = LineWriter::new(stream.try_clone()?);
-        let mut reader = BufReader::new(stream);
-
-        thread::spawn(move || Self::do_write(&mut writer, self.rx));
-
-        self.tx
-            .send(irc::Message {
-                command: irc::Command::USER,
-                params: vec![
-                    self.cfg.my_nick.clone(),
-                    "0".to_string(),
-                    "*".to_string(),
-                    "rust-irc-bot".to_string(),
-                ],
-            })
-            .unwrap();
-
-        self.tx
-            .send(irc::Message {
-                command: irc::Command::NICK,
-                params: vec![self.cfg.my_nick.clone()],
-            })
-            .unwrap();
+    /// A handle on the connection status, which stays current while the bot runs
+    pub fn status(&self) -> Arc<RwLock<Status>> {
+        self.status.clone()
+    }
+
+    /// Lets the config be reloaded from where it was loaded, when `source` asks for it or an
+    /// admin does
+    pub fn reloads_from(mut self, source: reload::Source) -> Bot {
+        self.reload = Some(source);
+        self
+    }
+
+    /// Connects, sends `text` to `target` and quits, without running any triggers.
+    /// Fails if the message couldn't be sent, without trying again.
+    pub fn deliver(mut self, target: &str, text: &str) -> std::io::Result<()> {
+        let casemapping = self.state.support().casemapping;
+        let key = self
+            .cfg
+            .network
+            .channels
+            .iter()
+            .find(|c| casemapping.same(&c.name, target))
+            .and_then(|c| c.key.clone());
+        self.delivery = Some(delivery::Delivery::new(target, text, key.as_deref()));
+        self.cfg.triggers.on_connect.enabled = false;
+        self.cfg.triggers.heartbeat.enabled = false;
+        self.cfg.triggers.reload.enabled = false;
+        self.cfg.triggers.modes.enabled = false;
+        match self.connect() {
+            Disconnect::Done => Ok(()),
+            Disconnect::Lost(reason) | Disconnect::Fatal(reason) => {
+                Err(std::io::Error::other(reason))
+            }
+        }
+    }
+
+    /// Connects and runs the bot, reconnecting whenever the connection is lost.
+    /// Only returns if the bot can't continue.
+    pub fn run(mut self) -> std::io::Result<()> {
         loop {
-            Bot::do_read(&self.tx, &mut reader);
+            let reason = match self.connect() {
+                Disconnect::Lost(reason) => reason,
+                Disconnect::Fatal(reason) => return Err(std::io::Error::other(reason)),
+                // nothing asks a running bot to quit
+                Disconnect::Done => return Ok(()),
+            };
+
+            let attempts = {
+                let mut status = self.status.write().unwrap();
+                status.connected = false;
+                status.attempts += 1;
+                status.last_error = Some(reason.clone());
+                status.attempts
+            };
+            let delay = self.cfg.reconnect.delay(attempts, backoff::random());
+            warn!(
+                "Disconnected: {}. Reconnecting in {:?} (attempt {})...",
+                reason, delay, attempts
+            );
+            thread::sleep(delay);
         }
     }
 
-    fn do_read(tx: &Sender<irc::Message>, reader: &mut BufReader<TcpStream>) {
+    // opens the transport, returning the halves for the reader and writer, and the socket
+    // underneath them so that the connection can be torn down from either side
+    fn open(network: &Network, keepalive: &keepalive::Keepalive) -> std::io::Result<Transport> {
+        let stream = TcpStream::connect((network.host.as_str(), network.port))?;
+        // don't let a stalled TLS handshake hang us forever
+        stream.set_read_timeout(Some(keepalive.idle + keepalive.timeout))?;
+        let sock = stream.try_clone()?;
+        if network.ssl {
+            let (reader, writer) = tls::connect(stream, &network.host, &network.tls)?;
+            return Ok((Box::new(reader), Box::new(writer), sock));
+        }
+        Ok((Box::new(stream.try_clone()?), Box::new(stream), sock))
+    }
+
+    // runs a single connection until it ends
+    fn connect(&mut self) -> Disconnect {
+        let (reader, writer, sock) = match Self::open(&self.cfg.network, &self.cfg.keepalive) {
+            Ok(transport) => transport,
+            Err(e) => return e.into(),
+        };
+        let mut writer = LineWriter::new(writer);
+        let mut reader = BufReader::new(reader);
+
+        let (tx, rx) = queue::channel(self.cfg.bulk_queue_limit, self.cfg.splitting.clone());
+        let writer_sock = match sock.try_clone() {
+            Ok(sock) => sock,
+            Err(e) => return e.into(),
+        };
+        let flood = self.cfg.flood.clone();
+        let charset = self.cfg.network.encoding.outgoing;
+        let writer_thread =
+            thread::spawn(move || Self::do_write(&mut writer, rx, writer_sock, flood, charset));
+        self.status.write().unwrap().connected = true;
+
+        self.state = State::new(&self.cfg.my_nick);
+        // the server password has to come before anything else that registers us
+        if let Some(pass) = &self.cfg.pass {
+            let pass = irc::Message::new(irc::Command::PASS, vec![pass.expose().to_string()]);
+            tx.send(pass).unwrap();
+        }
+        let mut negotiator = cap::Negotiator::new(&self.cfg.capabilities);
+        let mut authenticator = self.cfg.sasl.clone().map(sasl::Authenticator::new);
+        let mut nicks = nick::Recovery::new(&self.cfg.my_nick, &self.cfg.alt_nicks);
+        tx.send(negotiator.start()).unwrap();
+
+        tx.send(irc::Message::new(
+            irc::Command::USER,
+            vec![
+                self.cfg.username.clone(),
+                "0".to_string(),
+                "*".to_string(),
+                self.cfg.realname.clone(),
+            ],
+        ))
+        .unwrap();
+
+        tx.send(nicks.start()).unwrap();
+
+        let reason = self.do_read(
+            &tx,
+            &sock,
+            &mut reader,
+            &mut negotiator,
+            &mut authenticator,
+            &mut nicks,
+        );
+
+        // unblock the writer if it's stuck on a dead socket. once tx is dropped it drains and exits
+        sock.shutdown(Shutdown::Both).ok();
+        drop(tx);
+        writer_thread.join().ok();
+        match reason {
+            // the server hanging up is how a QUIT ends
+            Disconnect::Lost(_) if self.delivery.as_ref().is_some_and(|d| d.sent()) => {
+                Disconnect::Done
+            }
+            reason => reason,
+        }
+    }
+
+    fn do_read(
+        &mut self,
+        tx: &Sender,
+        sock: &TcpStream,
+        reader: &mut BufReader<Box<dyn Read + Send>>,
+        negotiator: &mut cap::Negotiator,
+        authenticator: &mut Option<sasl::Authenticator>,
+        nicks: &mut nick::Recovery,
+    ) -> Disconnect {
+        let mut monitor = keepalive::Monitor::new(&self.cfg.keepalive, Instant::now());
+        // a read that times out may leave a partial line behind, so the buffer outlives each read
+        let mut line = Vec::new();
         loop {
-            let mut line = String::new();
-            reader.read_line(&mut line).unwrap();
-            line.pop(); // Remove trailing \n
-            let msg = irc::parse_message(&line);
-            match msg {
-                Ok(m) => {
-                    if m.command != irc::Command::PING {
-                        println!("Received message: {}", &line);
-                    }
-                    for trigger in &[
-                        &triggers::on_connect::ON_CONNECT,
-                        &triggers::heartbeat::HEARTBEAT,
-                        &triggers::ping::PING,
-                    ] {
-                        // only run the action if the condition matches
-                        // if the action returns false, no need to run other actions on this message
-                        if trigger.condition(tx, &m) && !trigger.action(tx, &m) {
-                            continue;
-                        }
+            if self.reload.as_ref().is_some_and(|r| r.take_request()) {
+                self.reload_config(tx, None);
+            }
+            let now = Instant::now();
+            if let Some(ison) = nicks.poll(now) {
+                tx.send_with(ison, Priority::Bulk).unwrap();
+            }
+            let mut timeout = match nicks.read_timeout(now) {
+                Some(t) => t.min(monitor.read_timeout(now)),
+                None => monitor.read_timeout(now),
+            };
+            if self.reload.is_some() {
+                timeout = timeout.min(RELOAD_POLL);
+            }
+            if let Err(e) = sock.set_read_timeout(Some(timeout)) {
+                return e.into();
+            }
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => return Disconnect::Lost("connection closed by server".to_string()),
+                Ok(_) => monitor.activity(Instant::now()),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    match monitor.poll(Instant::now()) {
+                        Ok(Some(ping)) => tx.send(ping).unwrap(),
+                        Ok(None) => {}
+                        Err(reason) => return Disconnect::Lost(reason),
                     }
+                    continue;
                 }
+                Err(e) => return e.into(),
+            }
+            let bytes = std::mem::take(&mut line);
+            let (line, decoded) = encoding::decode(&bytes, self.cfg.network.encoding.fallback);
+            let line = line.trim_end_matches('\n'); // Remove trailing \n
+            let msg = match irc::MessageRef::parse(line) {
+                Ok(msg) => msg,
                 Err(e) => {
-                    println!(
+                    warn!(
                         "failed to parse incoming message: {} with error {}. continuing...",
-                        &line, e
+                        line, e
                     );
                     continue;
                 }
+            };
+            let owned = |msg: &irc::MessageRef| {
+                let mut m = irc::Message::from(msg.clone());
+                m.decoded = decoded;
+                m
+            };
+            // busy channels are mostly everyone else talking, which only triggers look at,
+            // so those lines are only copied if a trigger is going to act on them
+            let mut m = (!self.is_chatter(&msg)).then(|| owned(&msg));
+            // e.g. our own NickServ IDENTIFY, echoed back
+            let redacted = m
+                .as_ref()
+                .and_then(secret::redact)
+                .and_then(|r| r.serialize().ok());
+            let logged = redacted.as_deref().map_or(line, str::trim_end);
+            if decoded != encoding::Decoded::Utf8 {
+                debug!("Received message ({:?}): {}", decoded, logged);
+            } else if msg.command == irc::Command::PING {
+                trace!("Received message: {}", logged);
+            } else {
+                debug!("Received message: {}", logged);
+            }
+            if let Some(m) = &m {
+                if let Some(auth) = authenticator {
+                    match auth.handle(m) {
+                        Ok(resps) => resps.into_iter().for_each(|r| tx.send(r).unwrap()),
+                        Err(e) => {
+                            // don't leave the server waiting on us to finish registering
+                            tx.send(irc::Message::new(
+                                irc::Command::QUIT,
+                                vec!["SASL authentication failed".to_string()],
+                            ))
+                            .unwrap();
+                            return Disconnect::Fatal(e.to_string());
+                        }
+                    }
+                    if auth.in_progress() {
+                        negotiator.hold();
+                    } else if auth.succeeded() {
+                        *authenticator = None;
+                        if let Some(end) = negotiator.release() {
+                            tx.send(end).unwrap();
+                        }
+                    }
+                }
+                for resp in negotiator.handle(m) {
+                    if resp.params.first().is_some_and(|p| p == "END") {
+                        info!("Enabled capabilities: {:?}", negotiator.acknowledged());
+                    }
+                    tx.send(resp).unwrap();
+                }
+                if m.command == irc::Command::CAP {
+                    self.state.set_caps(negotiator.acknowledged());
+                    tx.set_client_tags(self.state.has_cap("message-tags"));
+                }
+                let resps = nicks.handle(m, &self.state, Instant::now());
+                match resps {
+                    Ok(resps) => resps.into_iter().for_each(|r| tx.send(r).unwrap()),
+                    Err(e) => {
+                        tx.send(irc::Message::new(irc::Command::QUIT, vec![e.clone()]))
+                            .unwrap();
+                        return Disconnect::Lost(e);
+                    }
+                }
+                if let Some(delivery) = &mut self.delivery {
+                    match delivery.handle(m, &self.state) {
+                        Ok(resps) => {
+                            for (resp, priority) in resps {
+                                tx.send_with(resp, priority).unwrap();
+                            }
+                        }
+                        Err(e) => {
+                            tx.send(irc::Message::new(irc::Command::QUIT, vec![]))
+                                .unwrap();
+                            return Disconnect::Fatal(e);
+                        }
+                    }
+                }
+                if let Some(reason) = self.track_connection(tx, m) {
+                    return reason;
+                }
+            }
+            let reload = Cell::new(None);
+            let ctx = triggers::Context {
+                config: &self.cfg,
+                state: &self.state,
+                tx,
+                now: Instant::now(),
+                reload: &reload,
+            };
+            let settings = &self.cfg.triggers;
+            for (enabled, trigger) in [
+                (
+                    settings.on_connect.enabled,
+                    &triggers::on_connect::ON_CONNECT,
+                ),
+                (settings.heartbeat.enabled, &triggers::heartbeat::HEARTBEAT),
+                (settings.reload.enabled, &triggers::reload::RELOAD),
+                (settings.modes.enabled, &triggers::modes::MODES),
+                // answering PINGs keeps us connected, so it can't be turned off
+                (true, &triggers::ping::PING),
+            ] {
+                if !enabled {
+                    continue;
+                }
+                // only run the action if the condition matches
+                if !trigger.condition(&ctx, &msg) {
+                    continue;
+                }
+                let m = m.get_or_insert_with(|| owned(&msg));
+                // if the action returns false, no need to run other actions on this message
+                if !trigger.action(&ctx, m) {
+                    continue;
+                }
+            }
+            if let Some(admin) = reload.take() {
+                self.reload_config(tx, Some(&admin));
             }
         }
     }
 
-    fn do_write(writer: &mut LineWriter<TcpStream>, rx: Receiver<irc::Message>) {
+    // what everyone else says, in channels or to us, which only triggers look at. our own
+    // messages echoed back aren't, since they show our prefix
+    fn is_chatter(&self, msg: &irc::MessageRef) -> bool {
+        matches!(
+            msg.command,
+            irc::Command::PRIVMSG | irc::Command::NOTICE | irc::Command::TAGMSG
+        ) && !msg.nick().is_some_and(|nick| self.state.is_me(nick))
+    }
+
+    // reads the config file again and switches to it, joining and parting channels to match.
+    // if the file is no good the running config is kept. what changed is logged, and sent
+    // to whoever asked for the reload
+    fn reload_config(&mut self, tx: &Sender, reply_to: Option<&str>) {
+        let report = match &self.reload {
+            None => vec!["there's no config file to reload".to_string()],
+            Some(source) => match config::load(&source.path, source.network.as_deref()) {
+                Err(e) => vec![format!("rejected, nothing changed: {}", e)],
+                Ok(cfg) => {
+                    let casemapping = self.state.support().casemapping;
+                    let changes = reload::diff(&self.cfg, &cfg, casemapping);
+                    let joins: Vec<Channel> = changes
+                        .joins
+                        .iter()
+                        .filter(|c| !self.channels.contains_key(&casemapping.fold(&c.name)))
+                        .cloned()
+                        .collect();
+                    // otherwise they're left for whenever autojoin is turned back on
+                    if cfg.triggers.on_connect.enabled {
+                        for join in on_connect::joins(&joins, self.state.support()) {
+                            tx.send_with(join, Priority::Bulk).unwrap();
+                        }
+                    }
+                    for name in &changes.parts {
+                        if self.channels.contains_key(&casemapping.fold(name)) {
+                            let part = irc::Message::new(irc::Command::PART, vec![name.clone()]);
+                            tx.send_with(part, Priority::Bulk).unwrap();
+                        }
+                    }
+                    self.cfg = cfg;
+                    changes.report()
+                }
+            },
+        };
+        for line in report {
+            info!("Reload: {}", line);
+            if let Some(nick) = reply_to {
+                let notice = irc::Message::new(irc::Command::NOTICE, vec![nick.to_string(), line]);
+                tx.send(notice).unwrap();
+            }
+        }
+    }
+
+    // keeps the status and the channel list current, returning a reason if the server
+    // is closing the connection
+    fn track_connection(&mut self, tx: &Sender, m: &irc::Message) -> Option<Disconnect> {
+        let casemapping = self.state.support().casemapping;
+        let from_me = m
+            .source
+            .as_ref()
+            .and_then(|s| s.nick())
+            .is_some_and(|nick| self.state.is_me(nick));
+        // anything we send that's relayed back to us, like a JOIN, shows our current prefix
+        if let Some(source @ irc::Source::User { host: Some(_), .. }) = &m.source {
+            if from_me {
+                tx.set_source(source.clone());
+            }
+        }
+        match m.command {
+            irc::Command::ERROR => {
+                return Some(Disconnect::Lost(format!(
+                    "server closed the connection: {}",
+                    m.params.first().unwrap_or(&String::new())
+                )));
+            }
+            irc::Command::RPL_WELCOME => {
+                let mut status = self.status.write().unwrap();
+                status.attempts = 0;
+                Self::learn_source(tx, m, casemapping);
+            }
+            // rejoin once ISUPPORT is all in, so the JOINs can be batched by TARGMAX
+            irc::Command::RPL_ENDOFMOTD | irc::Command::ERR_NOMOTD => {
+                // with SASL we're already logged in, or registration would have been aborted
+                if let (Some(nickserv), None) = (&self.cfg.nickserv, &self.cfg.sasl) {
+                    tx.send(nickserv.identify()).unwrap();
+                }
+                // the on_connect trigger joins the configured channels, with their keys
+                let autojoin = self.cfg.triggers.on_connect.enabled;
+                let rejoin: Vec<Channel> = self
+                    .channels
+                    .values()
+                    .filter(|name| {
+                        !autojoin
+                            || !self
+                                .cfg
+                                .network
+                                .channels
+                                .iter()
+                                .any(|c| casemapping.same(&c.name, name))
+                    })
+                    .map(|name| Channel {
+                        name: name.clone(),
+                        key: None,
+                    })
+                    .collect();
+                for join in on_connect::joins(&rejoin, self.state.support()) {
+                    tx.send_with(join, Priority::Bulk).unwrap();
+                }
+            }
+            irc::Command::JOIN if from_me && !m.params.is_empty() => {
+                // NAMES comes with the join, but only WHO says who everyone is
+                let who = irc::Message::new(irc::Command::WHO, vec![m.params[0].clone()]);
+                tx.send_with(who, Priority::Bulk).unwrap();
+                self.channels
+                    .entry(casemapping.fold(&m.params[0]))
+                    .or_insert_with(|| m.params[0].clone());
+            }
+            irc::Command::PART if from_me && !m.params.is_empty() => {
+                self.channels.remove(&casemapping.fold(&m.params[0]));
+            }
+            irc::Command::KICK if m.params.len() > 1 && self.state.is_me(&m.params[1]) => {
+                self.channels.remove(&casemapping.fold(&m.params[0]));
+            }
+            irc::Command::CHGHOST if from_me && m.params.len() > 1 => {
+                tx.set_source(irc::Source::User {
+                    nick: self.state.me().to_string(),
+                    user: Some(m.params[0].clone()),
+                    host: Some(m.params[1].clone()),
+                });
+            }
+            // the reply to the WHOIS sent by learn_source
+            irc::Command::RPL_WHOISUSER
+                if m.params.len() > 3 && casemapping.same(&m.params[1], &m.params[0]) =>
+            {
+                tx.set_source(irc::Source::User {
+                    nick: m.params[1].clone(),
+                    user: Some(m.params[2].clone()),
+                    host: Some(m.params[3].clone()),
+                });
+            }
+            _ => {}
+        }
+        self.state.update(m, SystemTime::now());
+        if m.command == irc::Command::RPL_ISUPPORT {
+            // channels kept from the last connection were folded by that server's rules
+            let casemapping = self.state.support().casemapping;
+            tx.set_support(self.state.support());
+            self.channels = std::mem::take(&mut self.channels)
+                .into_values()
+                .map(|c| (casemapping.fold(&c), c))
+                .collect();
+        }
+        None
+    }
+
+    // Work out our own prefix, which limits how much text fits in a message.
+    // Most servers end their welcome with it, otherwise ask for it.
+    fn learn_source(tx: &Sender, welcome: &irc::Message, casemapping: CaseMapping) {
+        let Some(nick) = welcome.params.first() else {
+            return;
+        };
+        let mask = welcome
+            .params
+            .last()
+            .and_then(|text| text.split(' ').next_back())
+            .map(irc::Source::parse);
+        match mask {
+            Some(
+                source @ irc::Source::User {
+                    user: Some(_),
+                    host: Some(_),
+                    ..
+                },
+            ) if source.nick().is_some_and(|n| casemapping.same(n, nick)) => tx.set_source(source),
+            _ => {
+                tx.set_source(irc::Source::User {
+                    nick: nick.clone(),
+                    user: None,
+                    host: None,
+                });
+                tx.send(irc::Message::new(irc::Command::WHOIS, vec![nick.clone()]))
+                    .unwrap();
+            }
+        }
+    }
+
+    fn do_write(
+        writer: &mut LineWriter<Box<dyn Write + Send>>,
+        rx: Receiver,
+        sock: TcpStream,
+        flood: throttle::FloodControl,
+        charset: encoding::Charset,
+    ) {
+        let mut throttle = throttle::Throttle::new(&flood, Instant::now());
+        let mut failed = false;
         loop {
-            match rx.recv() {
-                Ok(msg) => {
-                    // format into 'COMMAND ARG1 ARG2', truncate to 510, and append '/r/n'
-                    let mut output = msg.command.as_str().to_string();
-                    // TODO(raidancampbell): there's gotta be an easier/cheaper way to build the byte output
-                    output.push(' ');
-                    output.push_str(msg.params.join(" ").as_str());
-
-                    output.truncate(510);
-
-                    output.push('\r');
-                    output.push('\n');
-                    if msg.command != irc::Command::PONG {
-                        println!("Writing message: {}", &output);
+            // while the throttle is holding messages back, only critical ones may go out
+            let received = match throttle.wait(Instant::now()) {
+                wait if wait.is_zero() => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                wait => rx.recv_timeout(wait, Priority::Critical),
+            };
+            match received {
+                // keep draining so that senders don't fail while the reader notices the disconnect
+                Ok(_) if failed => {}
+                Ok((msg, priority)) => {
+                    if priority != Priority::Critical {
+                        throttle.acquire(Instant::now()).ok();
                     }
-                    writer.write_all(output.as_bytes()).unwrap();
+                    failed = !Self::write_message(writer, &sock, msg, charset, rx.line_len());
                 }
-                Err(e) => {
-                    println!("Channel closed with error {}", e);
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    debug!("Channel closed");
                     break;
                 }
             }
         }
-        println!("receiver closing...");
+        debug!("receiver closing...");
+    }
+
+    // returns false if the connection is no longer writable
+    fn write_message(
+        writer: &mut LineWriter<Box<dyn Write + Send>>,
+        sock: &TcpStream,
+        mut msg: irc::Message,
+        charset: encoding::Charset,
+        line_len: usize,
+    ) -> bool {
+        let mut output = match msg.serialize_within(line_len) {
+            Ok(output) => output,
+            Err(e) => {
+                warn!("not sending {:?}: {}", msg.command, e);
+                return true;
+            }
+        };
+        let redacted = secret::redact(&msg);
+        let shown = redacted.as_ref().and_then(|m| m.serialize().ok());
+        let shown = shown.as_deref().unwrap_or(&output).trim_end();
+        if msg.command == irc::Command::PONG {
+            trace!("Writing message: {}", shown);
+        } else {
+            debug!("Writing message: {}", shown);
+        }
+        let bytes = encoding::encode(&output, charset);
+        let written = writer.write_all(&bytes);
+        // don't leave credentials behind in freed memory
+        if redacted.is_some() {
+            if let Cow::Owned(mut bytes) = bytes {
+                bytes.zeroize();
+            }
+            output.zeroize();
+            msg.params.iter_mut().for_each(|p| p.zeroize());
+        }
+        if let Err(e) = written {
+            warn!(
+                "failed to write message: {}. dropping outgoing messages...",
+                e
+            );
+            // wake the reader up so that the connection gets torn down
+            sock.shutdown(Shutdown::Both).ok();
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::split;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    fn config(port: u16) -> Config {
+        Config {
+            my_nick: "ircrab".to_string(),
+            alt_nicks: vec![],
+            username: "ircrab".to_string(),
+            realname: "rust-irc-bot".to_string(),
+            network: Network {
+                host: "127.0.0.1".to_string(),
+                port,
+                ssl: false,
+                tls: tls::Options::default(),
+                channels: vec![],
+                encoding: encoding::Encoding::default(),
+            },
+            capabilities: vec![],
+            sasl: None,
+            nickserv: None,
+            pass: None,
+            reconnect: backoff::Backoff {
+                initial: Duration::from_millis(10),
+                max: Duration::from_millis(10),
+            },
+            keepalive: keepalive::Keepalive {
+                idle: Duration::from_millis(200),
+                timeout: Duration::from_millis(200),
+            },
+            flood: throttle::FloodControl {
+                burst: 10,
+                interval: Duration::from_millis(10),
+            },
+            bulk_queue_limit: 10,
+            splitting: split::Splitting {
+                max_lines: 4,
+                marker: "(continued)".to_string(),
+            },
+            triggers: triggers::Settings::default(),
+        }
+    }
+
+    // reads lines from the client until one matches
+    fn expect_line(reader: &mut BufReader<TcpStream>, want: &str) {
+        loop {
+            let mut line = String::new();
+            assert!(
+                reader.read_line(&mut line).unwrap() > 0,
+                "never got {}",
+                want
+            );
+            if line.trim_end() == want {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn reconnects_and_rejoins() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut cfg = config(listener.local_addr().unwrap().port());
+        cfg.network.channels = vec![Channel {
+            name: "#cwru".to_string(),
+            key: Some("hunter2".to_string()),
+        }];
+        cfg.triggers.on_connect.delay = Duration::ZERO;
+        let bot = new(cfg);
+        let status = bot.status();
+        thread::spawn(move || bot.run());
+
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(sock.try_clone().unwrap());
+        expect_line(&mut reader, "NICK ircrab");
+        sock.write_all(b":srv 001 ircrab :Welcome\r\n:srv 376 ircrab :End of /MOTD\r\n")
+            .unwrap();
+        expect_line(&mut reader, "JOIN #cwru hunter2");
+        sock.write_all(b":ircrab!u@h JOIN #CWRU\r\n:ircrab!u@h JOIN #foo\r\n")
+            .unwrap();
+        sock.write_all(b"ERROR :Closing Link: (Ping timeout)\r\n")
+            .unwrap();
+        drop(reader);
+        drop(sock);
+
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(sock.try_clone().unwrap());
+        {
+            let status = status.read().unwrap();
+            assert_eq!(status.attempts, 1);
+            assert_eq!(
+                status.last_error.as_deref(),
+                Some("server closed the connection: Closing Link: (Ping timeout)")
+            );
+        }
+        expect_line(&mut reader, "NICK ircrab");
+        sock.write_all(b":srv 001 ircrab :Welcome\r\n:srv 376 ircrab :End of /MOTD\r\n")
+            .unwrap();
+        // the configured channel is joined with its key, and the rest are rejoined
+        let mut joins = vec![];
+        while joins.len() < 2 {
+            let mut line = String::new();
+            assert!(reader.read_line(&mut line).unwrap() > 0);
+            if line.starts_with("JOIN ") {
+                joins.push(line.trim_end().to_string());
+            }
+        }
+        joins.sort();
+        assert_eq!(joins, vec!["JOIN #cwru hunter2", "JOIN #foo"]);
+        assert_eq!(status.read().unwrap().attempts, 0);
+        assert!(status.read().unwrap().connected);
+    }
+
+    #[test]
+    fn detects_dead_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let bot = new(config(listener.local_addr().unwrap().port()));
+        let status = bot.status();
+        thread::spawn(move || bot.run());
+
+        // accept, then go silent
+        let (sock, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(sock.try_clone().unwrap());
+        expect_line(&mut reader, "PING ircrab-keepalive");
+
+        listener.accept().unwrap();
+        let status = status.read().unwrap();
+        assert_eq!(status.attempts, 1);
+        assert!(status
+            .last_error
+            .as_ref()
+            .unwrap()
+            .starts_with("ping timeout"));
+    }
+
+    #[test]
+    fn survives_legacy_encodings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let bot = new(config(listener.local_addr().unwrap().port()));
+        thread::spawn(move || bot.run());
+
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(sock.try_clone().unwrap());
+        expect_line(&mut reader, "NICK ircrab");
+        sock.write_all(b":srv 001 ircrab :Welcome\r\n:foo!u@h PRIVMSG #foo :caf\xe9\r\n")
+            .unwrap();
+        sock.write_all(b":foo!u@h PRIVMSG #foo :!ping\r\n").unwrap();
+        expect_line(&mut reader, "PRIVMSG #foo pong!");
+    }
+
+    #[test]
+    fn tracks_channels_by_casemapping() {
+        let mut bot = new(config(0));
+        let (tx, _rx) = queue::channel(10, config(0).splitting);
+        for line in [
+            ":IRCrab!u@h JOIN #CWRU",
+            ":ircrab!u@h JOIN #cwru",
+            ":ircrab!u@h JOIN #[Bots]",
+            ":ircrab!u@h JOIN #rust",
+            ":ircrab!u@h PART #Rust",
+            ":op!u@h KICK #{bots} IRCRAB :bye",
+        ] {
+            bot.track_connection(&tx, &irc::parse_message(line).unwrap());
+        }
+        assert_eq!(bot.channels.values().collect::<Vec<_>>(), vec!["#CWRU"]);
+    }
+
+    #[test]
+    fn only_copies_chatter_for_triggers() {
+        let bot = new(config(0));
+        let chatter = |line| bot.is_chatter(&irc::MessageRef::parse(line).unwrap());
+        assert!(chatter(":alice!u@h PRIVMSG #cwru :hi"));
+        assert!(chatter("@+typing=active :alice!u@h TAGMSG #cwru"));
+        assert!(chatter(
+            ":irc.example.org NOTICE * :*** Looking up your hostname"
+        ));
+        assert!(!chatter(":IRCrab!u@h PRIVMSG #cwru :hi"));
+        assert!(!chatter(":alice!u@h JOIN #cwru"));
+        assert!(!chatter("PING :irc.example.org"));
+    }
+
+    #[test]
+    fn recovers_our_nick() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let bot = new(config(listener.local_addr().unwrap().port()));
+        thread::spawn(move || bot.run());
+
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(sock.try_clone().unwrap());
+        expect_line(&mut reader, "NICK ircrab");
+        sock.write_all(b":srv 433 * ircrab :Nickname is already in use\r\n")
+            .unwrap();
+        expect_line(&mut reader, "NICK ircrab_");
+        sock.write_all(b":srv 001 ircrab_ :Welcome\r\n:srv 376 ircrab_ :End of /MOTD\r\n")
+            .unwrap();
+        expect_line(&mut reader, "ISON ircrab");
+        sock.write_all(b":srv 303 ircrab_ :\r\n").unwrap();
+        expect_line(&mut reader, "NICK ircrab");
+        sock.write_all(b":ircrab_!u@h NICK ircrab\r\n").unwrap();
+
+        // triggers see the nick we have now
+        sock.write_all(b":foo!u@h PRIVMSG ircrab :!ping\r\n")
+            .unwrap();
+        expect_line(&mut reader, "PRIVMSG foo pong!");
+        // moved off it again, which only counts if the new nick was tracked
+        sock.write_all(b":ircrab!u@h NICK Guest42\r\n").unwrap();
+        expect_line(&mut reader, "ISON ircrab");
+    }
+
+    #[test]
+    fn reloads_for_admins() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let path = std::env::temp_dir().join(format!("ircrab-reload-{}.toml", port));
+        let mut cfg = config(port);
+        cfg.triggers.reload.accounts = vec!["admin".to_string()];
+        let bot = new(cfg).reloads_from(reload::Source::new(path.clone(), None));
+        thread::spawn(move || bot.run());
+
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(sock.try_clone().unwrap());
+        expect_line(&mut reader, "NICK ircrab");
+        sock.write_all(b":srv 001 ircrab :Welcome\r\n:srv 376 ircrab :End of /MOTD\r\n")
+            .unwrap();
+
+        // a broken file is turned away without touching the running config
+        std::fs::write(&path, "networks = []\n[identity]\nnick = \"1crab\"\n").unwrap();
+        sock.write_all(b"@account=admin :admin!u@h PRIVMSG ircrab :!reload\r\n")
+            .unwrap();
+        expect_line(
+            &mut reader,
+            "NOTICE admin :rejected, nothing changed: invalid `identity.nick`: `1crab` isn't a valid nick",
+        );
+
+        std::fs::write(
+            &path,
+            format!(
+                "[identity]\nnick = \"ircrab\"\n\
+                 [[networks]]\nname = \"test\"\nhost = \"localhost\"\nport = {}\ntls = false\n\
+                 channels = [{{ name = \"#new\" }}]\n\
+                 [connection]\ncapabilities = []\nbulk_queue_limit = 10\n\
+                 reconnect = {{ initial_ms = 10, max_ms = 10 }}\n\
+                 keepalive = {{ idle_ms = 200, timeout_ms = 200 }}\n\
+                 flood = {{ burst = 10, interval_ms = 10 }}\n\
+                 [triggers.heartbeat]\nreply = \"pong?\"\n\
+                 [triggers.reload]\naccounts = [\"admin\"]\n",
+                port
+            ),
+        )
+        .unwrap();
+        // only admins, logged in to their account, may reload
+        sock.write_all(b":admin!u@h PRIVMSG ircrab :!reload\r\n")
+            .unwrap();
+        sock.write_all(b"@account=admin :admin!u@h PRIVMSG ircrab :!reload\r\n")
+            .unwrap();
+        // the JOIN is bulk, so it may go out before or after the replies
+        let mut lines = vec![];
+        while lines.len() < 4 {
+            let mut line = String::new();
+            assert!(reader.read_line(&mut line).unwrap() > 0);
+            lines.push(line.trim_end().to_string());
+        }
+        std::fs::remove_file(&path).ok();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "JOIN #new",
+                "NOTICE admin :applied triggers.heartbeat",
+                "NOTICE admin :joining #new",
+                "NOTICE admin :pending reconnect: networks[0].host",
+            ]
+        );
+
+        sock.write_all(b":foo!u@h PRIVMSG #new :!ping\r\n").unwrap();
+        expect_line(&mut reader, "PRIVMSG #new pong?");
+    }
+
+    #[test]
+    fn reloads_for_channel_operators() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut cfg = config(listener.local_addr().unwrap().port());
+        cfg.triggers.reload.channels = vec!["#cwru".to_string()];
+        let bot = new(cfg);
+        thread::spawn(move || bot.run());
+
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(sock.try_clone().unwrap());
+        expect_line(&mut reader, "NICK ircrab");
+        sock.write_all(
+            b":srv 001 ircrab :Welcome\r\n:ircrab!u@h JOIN #cwru\r\n\
+              :srv 353 ircrab = #cwru :ircrab @alice bob\r\n\
+              :srv 366 ircrab #cwru :End of /NAMES list.\r\n\
+              :bob!u@h PRIVMSG ircrab :!reload\r\n\
+              :alice!u@h PRIVMSG ircrab :!reload\r\n",
+        )
+        .unwrap();
+        loop {
+            let mut line = String::new();
+            assert!(reader.read_line(&mut line).unwrap() > 0);
+            assert!(!line.starts_with("NOTICE bob"), "{}", line);
+            if line.trim_end() == "NOTICE alice :there's no config file to reload" {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn sets_modes_for_admins() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut cfg = config(listener.local_addr().unwrap().port());
+        cfg.triggers.modes.accounts = vec!["admin".to_string()];
+        let bot = new(cfg);
+        thread::spawn(move || bot.run());
+
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(sock.try_clone().unwrap());
+        expect_line(&mut reader, "NICK ircrab");
+        sock.write_all(
+            b":srv 001 ircrab :Welcome\r\n:ircrab!u@h JOIN #cwru\r\n\
+              :srv 353 ircrab = #cwru :@ircrab alice mallory\r\n\
+              :srv 366 ircrab #cwru :End of /NAMES list.\r\n",
+        )
+        .unwrap();
+        sock.write_all(b":mallory!u@h PRIVMSG ircrab :!op #cwru mallory\r\n")
+            .unwrap();
+        expect_line(
+            &mut reader,
+            "NOTICE mallory :only operators in #cwru can do that",
+        );
+        sock.write_all(b"@account=admin :admin!u@h PRIVMSG ircrab :!op #cwru alice\r\n")
+            .unwrap();
+        expect_line(&mut reader, "MODE #cwru +o alice");
+    }
+
+    #[test]
+    fn delivers_and_quits() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut cfg = config(listener.local_addr().unwrap().port());
+        cfg.network.channels = vec![Channel {
+            name: "#CWRU".to_string(),
+            key: Some("hunter2".to_string()),
+        }];
+        let bot = new(cfg);
+        let sent = thread::spawn(move || bot.deliver("#cwru", "deployed"));
+
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(sock.try_clone().unwrap());
+        expect_line(&mut reader, "NICK ircrab");
+        sock.write_all(b":srv 001 ircrab :Welcome\r\n:srv 376 ircrab :End of /MOTD\r\n")
+            .unwrap();
+        expect_line(&mut reader, "JOIN #cwru hunter2");
+        sock.write_all(b":ircrab!u@h JOIN #cwru\r\n").unwrap();
+        expect_line(&mut reader, "PRIVMSG #cwru deployed");
+        expect_line(&mut reader, "QUIT");
+        sock.write_all(b"ERROR :Closing Link: (Quit)\r\n").unwrap();
+        drop(sock);
+        assert!(sent.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn logs_in_to_the_server_and_services() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut cfg = config(listener.local_addr().unwrap().port());
+        cfg.pass = Some(secret::Secret::new("server-pw".to_string()));
+        cfg.nickserv = Some(crate::nickserv::NickServ {
+            account: Some("crab".to_string()),
+            password: secret::Secret::new("services-pw".to_string()),
+        });
+        let bot = new(cfg);
+        thread::spawn(move || bot.run());
+
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(sock.try_clone().unwrap());
+        let mut first = String::new();
+        reader.read_line(&mut first).unwrap();
+        assert_eq!(first, "PASS server-pw\r\n");
+        sock.write_all(b":srv 001 ircrab :Welcome\r\n:srv 422 ircrab :MOTD File is missing\r\n")
+            .unwrap();
+        expect_line(&mut reader, "PRIVMSG NickServ :IDENTIFY crab services-pw");
+    }
+
+    #[test]
+    fn learns_our_source() {
+        let (tx, rx) = queue::channel(10, config(0).splitting);
+        let welcome = irc::parse_message(
+            ":irc.example.org 001 ircrab :Welcome to the network ircrab!~ircrab@example.org",
+        )
+        .unwrap();
+        Bot::learn_source(&tx, &welcome, CaseMapping::Rfc1459);
+        assert!(rx.recv_timeout(Duration::ZERO, Priority::Bulk).is_err());
+
+        // without a mask in the welcome, ask the server
+        let welcome =
+            irc::parse_message(":irc.example.org 001 ircrab :Welcome to the network").unwrap();
+        Bot::learn_source(&tx, &welcome, CaseMapping::Rfc1459);
+        let (whois, _) = rx.recv_timeout(Duration::ZERO, Priority::Bulk).unwrap();
+        assert_eq!(whois.command, irc::Command::WHOIS);
+        assert_eq!(whois.params, vec!["ircrab"]);
     }
 }
@@ -0,0 +1,251 @@
+use crate::irc::{Command, Message};
+use std::collections::{BTreeMap, HashSet};
+
+// leave plenty of room for the prefix the server adds to its replies
+const MAX_REQ_LEN: usize = 400;
+
+/// Drives IRCv3 capability negotiation.
+/// See https://ircv3.net/specs/extensions/capability-negotiation
+pub struct Negotiator {
+    requested: Vec<String>,
+    available: BTreeMap<String, String>,
+    acknowledged: HashSet<String>,
+    // number of CAP REQ lines we're still waiting on an ACK or NAK for
+    outstanding: usize,
+    // true until we've sent CAP END (or registration completed without it)
+    registering: bool,
+    // set while something else, like SASL, needs registration held open
+    held: bool,
+}
+
+impl Negotiator {
+    pub fn new(requested: &[String]) -> Negotiator {
+        Negotiator {
+            requested: requested.to_vec(),
+            available: BTreeMap::new(),
+            acknowledged: HashSet::new(),
+            outstanding: 0,
+            registering: true,
+            held: false,
+        }
+    }
+
+    /// The message that kicks off negotiation. It must be sent before NICK and USER
+    /// so that the server holds registration until we send CAP END.
+    pub fn start(&self) -> Message {
+        Message::new(Command::CAP, vec!["LS".to_string(), "302".to_string()])
+    }
+
+    /// Keep registration open after negotiation completes, until `release` is called
+    pub fn hold(&mut self) {
+        self.held = true;
+    }
+
+    /// Stop holding registration open, returning CAP END if negotiation is otherwise finished
+    pub fn release(&mut self) -> Option<Message> {
+        self.held = false;
+        if self.registering && self.outstanding == 0 {
+            return Some(self.end());
+        }
+        None
+    }
+
+    pub fn acknowledged(&self) -> &HashSet<String> {
+        &self.acknowledged
+    }
+
+    /// Process an incoming message, returning any messages that should be sent in response
+    pub fn handle(&mut self, msg: &Message) -> Vec<Message> {
+        if msg.command == Command::RPL_WELCOME {
+            // the server doesn't support CAP, or registered us anyway
+            self.registering = false;
+            return vec![];
+        }
+        if msg.command != Command::CAP || msg.params.len() < 3 {
+            return vec![];
+        }
+
+        // params are: <nick or *> <subcommand> [*] <caps>
+        // the extra '*' marks a multi-line reply with more lines to come
+        let subcommand = msg.params[1].as_str();
+        let more = msg.params.len() > 3 && msg.params[2] == "*";
+        let caps = msg
+            .params
+            .last()
+            .unwrap()
+            .split(' ')
+            .filter(|c| !c.is_empty());
+
+        let mut out = vec![];
+        match subcommand {
+            "LS" => {
+                self.add_available(caps);
+                if !more && self.registering {
+                    out = self.request(|_| true);
+                }
+            }
+            "NEW" => {
+                let new: Vec<&str> = caps.collect();
+                self.add_available(new.iter().copied());
+                out = self.request(|c| new.iter().any(|n| cap_name(n) == c));
+            }
+            "DEL" => {
+                for cap in caps {
+                    self.available.remove(cap);
+                    self.acknowledged.remove(cap);
+                }
+            }
+            "ACK" => {
+                for cap in caps {
+                    match cap.strip_prefix('-') {
+                        Some(removed) => self.acknowledged.remove(removed),
+                        None => self.acknowledged.insert(cap.to_string()),
+                    };
+                }
+                if !more {
+                    self.outstanding = self.outstanding.saturating_sub(1);
+                }
+            }
+            "NAK" if !more => {
+                self.outstanding = self.outstanding.saturating_sub(1);
+            }
+            _ => {}
+        }
+
+        // registration is held until we send CAP END, so send it once there's nothing left
+        // to wait for: either none of the caps we want are available, or all our REQs are answered
+        let finished = match subcommand {
+            "LS" => out.is_empty(),
+            "ACK" | "NAK" => self.outstanding == 0,
+            _ => false,
+        };
+        if self.registering && !self.held && !more && finished {
+            out.push(self.end());
+        }
+        out
+    }
+
+    fn end(&mut self) -> Message {
+        self.registering = false;
+        Message::new(Command::CAP, vec!["END".to_string()])
+    }
+
+    fn add_available<'a>(&mut self, caps: impl Iterator<Item = &'a str>) {
+        for cap in caps {
+            let (name, value) = cap.split_once('=').unwrap_or((cap, ""));
+            self.available.insert(name.to_string(), value.to_string());
+        }
+    }
+
+    // builds CAP REQ lines for every requested capability that is available, not yet
+    // acknowledged, and matches the filter
+    fn request(&mut self, filter: impl Fn(&str) -> bool) -> Vec<Message> {
+        let wanted: Vec<&String> = self
+            .requested
+            .iter()
+            .filter(|c| self.available.contains_key(*c))
+            .filter(|c| !self.acknowledged.contains(*c))
+            .filter(|c| filter(c))
+            .collect();
+
+        let mut lines: Vec<String> = vec![];
+        for cap in wanted {
+            match lines.last_mut() {
+                Some(line) if line.len() + cap.len() < MAX_REQ_LEN => {
+                    line.push(' ');
+                    line.push_str(cap);
+                }
+                _ => lines.push(cap.clone()),
+            }
+        }
+
+        self.outstanding += lines.len();
+        lines
+            .into_iter()
+            .map(|l| Message::new(Command::CAP, vec!["REQ".to_string(), l]))
+            .collect()
+    }
+}
+
+// strips any value from a capability as advertised in LS or NEW, e.g. `sasl=PLAIN,EXTERNAL`
+fn cap_name(cap: &str) -> &str {
+    cap.split_once('=').map(|(name, _)| name).unwrap_or(cap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::parse_message;
+
+    fn negotiator() -> Negotiator {
+        Negotiator::new(&[
+            "server-time".to_string(),
+            "message-tags".to_string(),
+            "sasl".to_string(),
+        ])
+    }
+
+    fn handle(n: &mut Negotiator, line: &str) -> Vec<String> {
+        n.handle(&parse_message(line).unwrap())
+            .into_iter()
+            .map(|m| m.params.join(" "))
+            .collect()
+    }
+
+    #[test]
+    fn requests_available_caps() {
+        let mut n = negotiator();
+        assert!(handle(&mut n, ":srv CAP * LS * :multi-prefix server-time").is_empty());
+        assert_eq!(
+            handle(&mut n, ":srv CAP * LS :sasl=PLAIN,EXTERNAL away-notify"),
+            vec!["REQ server-time sasl"]
+        );
+        assert_eq!(n.available["sasl"], "PLAIN,EXTERNAL");
+        assert_eq!(
+            handle(&mut n, ":srv CAP * ACK :server-time sasl"),
+            vec!["END"]
+        );
+        assert!(n.acknowledged().contains("sasl"));
+        assert!(!n.acknowledged().contains("message-tags"));
+    }
+
+    #[test]
+    fn ends_when_nothing_is_available() {
+        let mut n = negotiator();
+        assert_eq!(handle(&mut n, ":srv CAP * LS :away-notify"), vec!["END"]);
+    }
+
+    #[test]
+    fn ends_on_nak() {
+        let mut n = negotiator();
+        assert_eq!(handle(&mut n, ":srv CAP * LS :sasl"), vec!["REQ sasl"]);
+        assert_eq!(handle(&mut n, ":srv CAP * NAK :sasl"), vec!["END"]);
+        assert!(n.acknowledged().is_empty());
+    }
+
+    #[test]
+    fn holds_registration_open() {
+        let mut n = negotiator();
+        handle(&mut n, ":srv CAP * LS :sasl");
+        n.hold();
+        assert!(handle(&mut n, ":srv CAP * ACK :sasl").is_empty());
+        assert_eq!(n.release().unwrap().params, vec!["END"]);
+        assert!(n.release().is_none());
+    }
+
+    #[test]
+    fn handles_new_and_del() {
+        let mut n = negotiator();
+        handle(&mut n, ":srv CAP * LS :server-time");
+        handle(&mut n, ":srv CAP * ACK :server-time");
+        assert_eq!(
+            handle(&mut n, ":srv CAP ircrab NEW :message-tags batch"),
+            vec!["REQ message-tags"]
+        );
+        // registration is already over, so no END this time
+        assert!(handle(&mut n, ":srv CAP ircrab ACK :message-tags").is_empty());
+        assert!(n.acknowledged().contains("message-tags"));
+        handle(&mut n, ":srv CAP ircrab DEL :message-tags");
+        assert!(!n.acknowledged().contains("message-tags"));
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+
+/// How the server folds the case of nicks and channel names, from ISUPPORT's CASEMAPPING.
+/// See https://modern.ircdocs.horse/#casemapping-parameter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaseMapping {
+    // only A-Z and a-z
+    Ascii,
+    // ascii, plus []\~ are the uppercase of {}|^, a relic of the Scandinavian origins of IRC
+    Rfc1459,
+    // rfc1459 without ~ and ^
+    Rfc1459Strict,
+}
+
+impl CaseMapping {
+    /// The casemapping named by a CASEMAPPING token. Anything unrecognized is treated as
+    /// rfc1459, since that's the default and folds the most characters.
+    pub fn from_token(name: &str) -> CaseMapping {
+        match name {
+            "ascii" => CaseMapping::Ascii,
+            "rfc1459-strict" | "strict-rfc1459" => CaseMapping::Rfc1459Strict,
+            _ => CaseMapping::Rfc1459,
+        }
+    }
+
+    fn fold_char(self, c: char) -> char {
+        match (self, c) {
+            (_, 'A'..='Z') => c.to_ascii_lowercase(),
+            (CaseMapping::Rfc1459 | CaseMapping::Rfc1459Strict, '[') => '{',
+            (CaseMapping::Rfc1459 | CaseMapping::Rfc1459Strict, ']') => '}',
+            (CaseMapping::Rfc1459 | CaseMapping::Rfc1459Strict, '\\') => '|',
+            (CaseMapping::Rfc1459, '~') => '^',
+            _ => c,
+        }
+    }
+
+    /// Folds a nick or channel name into the key it should be looked up by
+    pub fn fold(self, s: &str) -> Folded {
+        Folded(s.chars().map(|c| self.fold_char(c)).collect())
+    }
+
+    /// Returns true if the two nicks or channel names are the same to the server
+    pub fn same(self, a: &str, b: &str) -> bool {
+        a.len() == b.len()
+            && a.chars()
+                .zip(b.chars())
+                .all(|(a, b)| self.fold_char(a) == self.fold_char(b))
+    }
+}
+
+/// A nick or channel name in its case folded form, for keying maps
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Folded(String);
+
+impl fmt::Display for Folded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_ascii() {
+        let m = CaseMapping::Ascii;
+        assert!(m.same("#CWRU", "#cwru"));
+        assert!(!m.same("[Bot]", "{bot}"));
+        assert!(!m.same("a~", "a^"));
+        assert_eq!(m.fold("NiCk[]").to_string(), "nick[]");
+        // only ASCII letters fold, whatever unicode thinks of the rest
+        assert!(!m.same("É", "é"));
+    }
+
+    #[test]
+    fn folds_rfc1459() {
+        let m = CaseMapping::Rfc1459;
+        assert!(m.same("#CWRU", "#cwru"));
+        assert!(m.same("[Bot]", "{bot}"));
+        assert!(m.same("a\\~", "A|^"));
+        assert_eq!(m.fold("[Bot]\\~"), m.fold("{BOT}|^"));
+        assert!(!m.same("bot", "bot_"));
+    }
+
+    #[test]
+    fn folds_rfc1459_strict() {
+        let m = CaseMapping::Rfc1459Strict;
+        assert!(m.same("[Bot]\\", "{bot}|"));
+        assert!(!m.same("a~", "a^"));
+    }
+
+    #[test]
+    fn reads_tokens() {
+        assert_eq!(CaseMapping::from_token("ascii"), CaseMapping::Ascii);
+        assert_eq!(
+            CaseMapping::from_token("rfc1459-strict"),
+            CaseMapping::Rfc1459Strict
+        );
+        assert_eq!(CaseMapping::from_token("rfc7613"), CaseMapping::Rfc1459);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::irc::{Message, Source};
+use crate::{config, encoding, log};
+use clap::{ArgAction, Parser, Subcommand};
+use std::path::PathBuf;
+
+/// A very basic IRC bot
+#[derive(Parser)]
+#[command(name = "ircrab", version)]
+pub struct Cli {
+    /// The config file
+    #[arg(long, global = true, env = config::PATH_ENV, default_value = config::DEFAULT_PATH)]
+    pub config: PathBuf,
+    /// Which of the config's networks to connect to. Defaults to the first
+    #[arg(long, global = true)]
+    pub network: Option<String>,
+    /// Log more: -v for every line sent and received, -vv for keepalives too
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Log less: -q for only warnings and errors, -qq for only errors
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "verbose")]
+    pub quiet: u8,
+    /// How log lines are written
+    #[arg(long, global = true, value_enum, default_value_t = log::Format::Text)]
+    pub log_format: log::Format,
+    // the spelling from before there were subcommands, kept for existing scripts
+    #[arg(long, global = true, hide = true)]
+    pub print_default_config: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Connect and run the bot. This is the default
+    Run,
+    /// Check the config file, then exit
+    CheckConfig,
+    /// Print a config file to start from
+    PrintDefaultConfig,
+    /// Connect, send a message, and quit
+    Send {
+        /// The channel or nick to send to
+        #[arg(long)]
+        target: String,
+        text: String,
+    },
+    /// Parse raw IRC lines from stdin and print each as JSON, for debugging
+    Parse,
+}
+
+impl Cli {
+    /// The subcommand to run, which is `run` if none was given
+    pub fn subcommand(&self) -> Command {
+        if self.print_default_config {
+            return Command::PrintDefaultConfig;
+        }
+        self.command.clone().unwrap_or(Command::Run)
+    }
+
+    pub fn log_level(&self) -> log::Level {
+        log::Level::from_verbosity(i32::from(self.verbose) - i32::from(self.quiet))
+    }
+}
+
+/// A parsed message as a JSON object, for `ircrab parse`
+pub fn to_json(msg: &Message) -> serde_json::Value {
+    let source = match &msg.source {
+        Some(Source::User { nick, user, host }) => {
+            serde_json::json!({"nick": nick, "user": user, "host": host})
+        }
+        Some(Source::Server(name)) => serde_json::json!({ "server": name }),
+        None => serde_json::Value::Null,
+    };
+    let mut json = serde_json::json!({
+        "tags": msg.tags,
+        "source": source,
+        "command": msg.command.as_str(),
+        "params": msg.params,
+    });
+    if msg.decoded != encoding::Decoded::Utf8 {
+        json["decoded"] = format!("{:?}", msg.decoded).into();
+    }
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::parse_message;
+    use clap::CommandFactory;
+
+    #[test]
+    fn parses_arguments() {
+        Cli::command().debug_assert();
+        let cli = Cli::try_parse_from(["ircrab"]).unwrap();
+        assert_eq!(cli.subcommand(), Command::Run);
+        assert_eq!(cli.log_level(), log::Level::Info);
+
+        let cli = Cli::try_parse_from([
+            "ircrab",
+            "-vv",
+            "send",
+            "--network",
+            "libera",
+            "--target",
+            "#cwru",
+            "hello there",
+        ])
+        .unwrap();
+        assert_eq!(cli.network.as_deref(), Some("libera"));
+        assert_eq!(cli.log_level(), log::Level::Trace);
+        assert_eq!(
+            cli.command,
+            Some(Command::Send {
+                target: "#cwru".to_string(),
+                text: "hello there".to_string()
+            })
+        );
+
+        let cli =
+            Cli::try_parse_from(["ircrab", "check-config", "--config", "x.toml", "-q"]).unwrap();
+        assert_eq!(cli.config, PathBuf::from("x.toml"));
+        assert_eq!(cli.log_level(), log::Level::Warn);
+        assert!(Cli::try_parse_from(["ircrab", "send", "hello"]).is_err());
+        assert!(Cli::try_parse_from(["ircrab", "--log-format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn keeps_the_old_flag() {
+        for args in [
+            &["ircrab", "print-default-config"][..],
+            &["ircrab", "--print-default-config"],
+        ] {
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert_eq!(cli.subcommand(), Command::PrintDefaultConfig, "{:?}", args);
+        }
+        let help = Cli::command().render_long_help().to_string();
+        assert!(!help.contains("--print-default-config"), "{}", help);
+    }
+
+    #[test]
+    fn prints_messages_as_json() {
+        let msg = parse_message("@msgid=abc :nick!user@host PRIVMSG #cwru :hi there").unwrap();
+        assert_eq!(
+            to_json(&msg),
+            serde_json::json!({
+                "tags": {"msgid": "abc"},
+                "source": {"nick": "nick", "user": "user", "host": "host"},
+                "command": "PRIVMSG",
+                "params": ["#cwru", "hi there"],
+            })
+        );
+        let msg = parse_message(":irc.example.org 001 ircrab :Welcome").unwrap();
+        assert_eq!(
+            to_json(&msg)["source"],
+            serde_json::json!({"server": "irc.example.org"})
+        );
+        assert_eq!(to_json(&msg)["command"], "001");
+    }
+}
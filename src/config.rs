@@ -0,0 +1,1077 @@
+use crate::encoding::{Charset, Encoding};
+use crate::nickserv::NickServ;
+use crate::sasl::Mechanism;
+use crate::secret::Secret;
+use crate::{backoff, keepalive, split, throttle, tls, triggers, Channel, Config, Network};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fmt, fs, io};
+
+/// Where the config file is looked for when no path is given
+pub const DEFAULT_PATH: &str = "ircrab.toml";
+
+/// The environment variable that can point at the config file instead of `--config`
+pub const PATH_ENV: &str = "IRCRAB_CONFIG";
+
+/// A config file to start from, printed by `ircrab print-default-config`
+pub const SAMPLE: &str = r###"# ircrab configuration
+
+[identity]
+nick = "ircrab"
+# tried in order if the nick is taken, before ircrab_, ircrab1, ircrab2 and so on
+alt_nicks = []
+# defaults to the nick
+username = "ircrab"
+realname = "rust-irc-bot"
+
+# ircrab connects to the first network listed
+[[networks]]
+name = "libera"
+host = "irc.libera.chat"
+# defaults to 6697 with TLS and 6667 without
+port = 6697
+tls = true
+# trust the server's certificate if and only if it has this SHA-256 fingerprint
+# tls_fingerprint = "0f1e2d..."
+# skip certificate verification entirely, only for private networks with self-signed certs
+# tls_accept_invalid_certs = false
+# a PEM client certificate, and its key if it's in a separate file
+# tls_client_cert = "/etc/ircrab/client.pem"
+# tls_client_key = "/etc/ircrab/client.key"
+# what to decode lines as when they aren't valid UTF-8: "utf-8", "latin-1" or "cp1252"
+fallback_encoding = "cp1252"
+# what to send lines as
+encoding = "utf-8"
+
+# passwords never go in this file. each is read from a file with `password_file`,
+# or from an environment variable with `password_env`
+#
+# log in to services with SASL. mechanism is "plain" or "external", which uses the TLS
+# client certificate and needs no password
+# [networks.sasl]
+# mechanism = "plain"
+# username = "ircrab"
+# password_file = "/run/secrets/ircrab-sasl"
+#
+# or identify with NickServ, on networks without SASL
+# [networks.nickserv]
+# account = "ircrab"
+# password_env = "IRCRAB_NICKSERV"
+#
+# the server password, if the server wants one
+# [networks.pass]
+# password_env = "IRCRAB_PASS"
+
+[[networks.channels]]
+name = "##cwru-testing"
+
+# [[networks.channels]]
+# name = "#private"
+# key = "hunter2"
+
+[connection]
+# IRCv3 capabilities to request, if the server offers them. SASL needs "sasl"
+capabilities = [
+    "server-time",
+    "message-tags",
+    "account-notify",
+    "account-tag",
+    "away-notify",
+    "extended-join",
+    "multi-prefix",
+    "batch",
+    "echo-message",
+    "chghost",
+    "sasl",
+]
+# how many low priority lines, like autojoins, may wait to be sent before the oldest are dropped
+bulk_queue_limit = 50
+
+[connection.reconnect]
+# the wait before reconnecting doubles with each failed attempt, from initial_ms up to max_ms
+initial_ms = 2000
+max_ms = 300000
+
+[connection.keepalive]
+# send a PING after this long without hearing from the server
+idle_ms = 120000
+# and reconnect if nothing arrives this long after it
+timeout_ms = 60000
+
+[connection.flood]
+# how many lines can be sent back to back, and how long it takes to earn back one more
+burst = 5
+interval_ms = 2000
+
+[connection.splitting]
+# the most lines one reply can take up. anything past that is dropped
+max_lines = 4
+# the end of every line that's continued on the next
+marker = "(continued)"
+
+[triggers.heartbeat]
+enabled = true
+# replies to this message with the reply
+command = "!ping"
+reply = "pong!"
+
+[triggers.on_connect]
+enabled = true
+# how long to wait after registering before joining channels
+delay_ms = 1000
+
+[triggers.reload]
+enabled = true
+# reloads this file when sent privately by someone logged in to one of the accounts,
+# or by an operator in one of the channels. sending the bot a SIGHUP does the same
+command = "!reload"
+accounts = []
+channels = []
+
+[triggers.modes]
+enabled = true
+# sets modes when sent privately one of !op, !deop, !voice, !devoice, !ban or !unban,
+# with a channel and the nicks or masks. someone logged in to one of the accounts can ask
+# for it in any channel, and an operator in their own channels
+accounts = []
+"###;
+
+/// Why a config file couldn't be used
+#[derive(Debug)]
+pub enum ConfigErr {
+    Io(PathBuf, io::Error),
+    // not valid TOML, or a key with the wrong type or name
+    Parse(toml::de::Error),
+    // a key whose value doesn't make sense
+    Invalid { key: String, problem: String },
+}
+
+impl fmt::Display for ConfigErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigErr::Io(path, e) => write!(f, "couldn't read {}: {}", path.display(), e),
+            ConfigErr::Parse(e) => write!(f, "{}", e.to_string().trim_end()),
+            ConfigErr::Invalid { key, problem } => write!(f, "invalid `{}`: {}", key, problem),
+        }
+    }
+}
+
+fn invalid(key: impl Into<String>, problem: impl Into<String>) -> ConfigErr {
+    ConfigErr::Invalid {
+        key: key.into(),
+        problem: problem.into(),
+    }
+}
+
+// The layout of the file. Everything is checked by `validate` before it becomes a Config.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    identity: Identity,
+    networks: Vec<NetworkFile>,
+    #[serde(default)]
+    connection: Connection,
+    #[serde(default)]
+    triggers: Triggers,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Identity {
+    nick: String,
+    #[serde(default)]
+    alt_nicks: Vec<String>,
+    username: Option<String>,
+    #[serde(default = "default_realname")]
+    realname: String,
+}
+
+fn default_realname() -> String {
+    "rust-irc-bot".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NetworkFile {
+    name: String,
+    host: String,
+    port: Option<u16>,
+    #[serde(default = "yes")]
+    tls: bool,
+    tls_fingerprint: Option<String>,
+    #[serde(default)]
+    tls_accept_invalid_certs: bool,
+    tls_client_cert: Option<PathBuf>,
+    tls_client_key: Option<PathBuf>,
+    #[serde(default = "default_fallback")]
+    fallback_encoding: CharsetName,
+    #[serde(default = "default_encoding")]
+    encoding: CharsetName,
+    #[serde(default)]
+    channels: Vec<ChannelFile>,
+    sasl: Option<SaslFile>,
+    nickserv: Option<NickServFile>,
+    pass: Option<PasswordFile>,
+}
+
+// passwords are only ever referred to, never written out in the file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SaslFile {
+    #[serde(default)]
+    mechanism: MechanismName,
+    username: Option<String>,
+    password_file: Option<PathBuf>,
+    password_env: Option<String>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum MechanismName {
+    #[default]
+    Plain,
+    External,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NickServFile {
+    account: Option<String>,
+    password_file: Option<PathBuf>,
+    password_env: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PasswordFile {
+    password_file: Option<PathBuf>,
+    password_env: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChannelFile {
+    name: String,
+    key: Option<String>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+enum CharsetName {
+    #[serde(rename = "utf-8")]
+    Utf8,
+    #[serde(rename = "latin-1")]
+    Latin1,
+    #[serde(rename = "cp1252")]
+    Cp1252,
+}
+
+impl From<CharsetName> for Charset {
+    fn from(name: CharsetName) -> Charset {
+        match name {
+            CharsetName::Utf8 => Charset::Utf8,
+            CharsetName::Latin1 => Charset::Latin1,
+            CharsetName::Cp1252 => Charset::Cp1252,
+        }
+    }
+}
+
+fn default_fallback() -> CharsetName {
+    CharsetName::Cp1252
+}
+
+fn default_encoding() -> CharsetName {
+    CharsetName::Utf8
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct Connection {
+    capabilities: Vec<String>,
+    reconnect: Reconnect,
+    keepalive: Keepalive,
+    flood: Flood,
+    bulk_queue_limit: usize,
+    splitting: Splitting,
+}
+
+impl Default for Connection {
+    fn default() -> Connection {
+        Connection {
+            capabilities: [
+                "server-time",
+                "message-tags",
+                "account-notify",
+                // who sent a message, for the reload trigger
+                "account-tag",
+                "away-notify",
+                "extended-join",
+                "multi-prefix",
+                "batch",
+                "echo-message",
+                "chghost",
+                "sasl",
+            ]
+            .iter()
+            .map(|c| c.to_string())
+            .collect(),
+            reconnect: Reconnect::default(),
+            keepalive: Keepalive::default(),
+            flood: Flood::default(),
+            bulk_queue_limit: 50,
+            splitting: Splitting::default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct Reconnect {
+    initial_ms: u64,
+    max_ms: u64,
+}
+
+impl Default for Reconnect {
+    fn default() -> Reconnect {
+        Reconnect {
+            initial_ms: 2000,
+            max_ms: 300_000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct Keepalive {
+    idle_ms: u64,
+    timeout_ms: u64,
+}
+
+impl Default for Keepalive {
+    fn default() -> Keepalive {
+        Keepalive {
+            idle_ms: 120_000,
+            timeout_ms: 60_000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct Flood {
+    burst: u32,
+    interval_ms: u64,
+}
+
+impl Default for Flood {
+    fn default() -> Flood {
+        Flood {
+            burst: 5,
+            interval_ms: 2000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct Splitting {
+    max_lines: usize,
+    marker: String,
+}
+
+impl Default for Splitting {
+    fn default() -> Splitting {
+        Splitting {
+            max_lines: 4,
+            marker: "(continued)".to_string(),
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Triggers {
+    #[serde(default)]
+    heartbeat: Heartbeat,
+    #[serde(default)]
+    on_connect: OnConnect,
+    #[serde(default)]
+    reload: Reload,
+    #[serde(default)]
+    modes: Modes,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct Heartbeat {
+    enabled: bool,
+    command: String,
+    reply: String,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Heartbeat {
+        Heartbeat {
+            enabled: true,
+            command: "!ping".to_string(),
+            reply: "pong!".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct OnConnect {
+    enabled: bool,
+    delay_ms: u64,
+}
+
+impl Default for OnConnect {
+    fn default() -> OnConnect {
+        OnConnect {
+            enabled: true,
+            delay_ms: 1000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct Reload {
+    enabled: bool,
+    command: String,
+    accounts: Vec<String>,
+    channels: Vec<String>,
+}
+
+impl Default for Reload {
+    fn default() -> Reload {
+        Reload {
+            enabled: true,
+            command: "!reload".to_string(),
+            accounts: vec![],
+            channels: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct Modes {
+    enabled: bool,
+    accounts: Vec<String>,
+}
+
+impl Default for Modes {
+    fn default() -> Modes {
+        Modes {
+            enabled: true,
+            accounts: vec![],
+        }
+    }
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// Reads and validates the config file at `path`, setting the bot up for the named network,
+/// or the first one if none is named
+pub fn load(path: &Path, network: Option<&str>) -> Result<Config, ConfigErr> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigErr::Io(path.to_path_buf(), e))?;
+    parse(&text, network)
+}
+
+/// Parses and validates the text of a config file
+pub fn parse(text: &str, network: Option<&str>) -> Result<Config, ConfigErr> {
+    let file: File = toml::from_str(text).map_err(ConfigErr::Parse)?;
+    validate(&file)?;
+    file.into_config(network)
+}
+
+fn validate(file: &File) -> Result<(), ConfigErr> {
+    let id = &file.identity;
+    check_nick("identity.nick", &id.nick)?;
+    for (i, nick) in id.alt_nicks.iter().enumerate() {
+        check_nick(&format!("identity.alt_nicks[{}]", i), nick)?;
+    }
+    if let Some(username) = &id.username {
+        if username.is_empty() || username.contains([' ', '@', '\r', '\n', '\0']) {
+            return Err(invalid(
+                "identity.username",
+                "must be a single word without '@'",
+            ));
+        }
+    }
+    if id.realname.contains(['\r', '\n', '\0']) {
+        return Err(invalid("identity.realname", "can't contain line breaks"));
+    }
+
+    let conn = &file.connection;
+    for (i, cap) in conn.capabilities.iter().enumerate() {
+        if cap.is_empty() || cap.contains(|c: char| c.is_whitespace() || c.is_control()) {
+            return Err(invalid(
+                format!("connection.capabilities[{}]", i),
+                format!("`{}` isn't a capability name", cap),
+            ));
+        }
+    }
+    if conn.reconnect.initial_ms == 0 {
+        return Err(invalid(
+            "connection.reconnect.initial_ms",
+            "must be more than 0",
+        ));
+    }
+    if conn.reconnect.max_ms < conn.reconnect.initial_ms {
+        return Err(invalid(
+            "connection.reconnect.max_ms",
+            "can't be less than initial_ms",
+        ));
+    }
+    // a zero read timeout isn't a timeout at all
+    if conn.keepalive.idle_ms == 0 {
+        return Err(invalid(
+            "connection.keepalive.idle_ms",
+            "must be more than 0",
+        ));
+    }
+    if conn.keepalive.timeout_ms == 0 {
+        return Err(invalid(
+            "connection.keepalive.timeout_ms",
+            "must be more than 0",
+        ));
+    }
+    // nothing could ever be sent
+    if conn.flood.burst == 0 {
+        return Err(invalid("connection.flood.burst", "must be at least 1"));
+    }
+    if conn.bulk_queue_limit == 0 {
+        return Err(invalid("connection.bulk_queue_limit", "must be at least 1"));
+    }
+    if conn.splitting.max_lines == 0 {
+        return Err(invalid(
+            "connection.splitting.max_lines",
+            "must be at least 1",
+        ));
+    }
+    if conn.splitting.marker.contains(['\r', '\n', '\0']) {
+        return Err(invalid(
+            "connection.splitting.marker",
+            "can't contain line breaks",
+        ));
+    }
+    for (i, channel) in file.triggers.reload.channels.iter().enumerate() {
+        check_channel(&format!("triggers.reload.channels[{}]", i), channel)?;
+    }
+
+    if file.networks.is_empty() {
+        return Err(invalid("networks", "at least one network is needed"));
+    }
+    for (i, network) in file.networks.iter().enumerate() {
+        let key = |field: &str| format!("networks[{}].{}", i, field);
+        if network.name.is_empty() {
+            return Err(invalid(key("name"), "can't be empty"));
+        }
+        if file.networks[..i].iter().any(|n| n.name == network.name) {
+            return Err(invalid(
+                key("name"),
+                format!("`{}` is used by more than one network", network.name),
+            ));
+        }
+        if network.host.is_empty() || network.host.contains(char::is_whitespace) {
+            return Err(invalid(key("host"), "must be a hostname or address"));
+        }
+        if network.port == Some(0) {
+            return Err(invalid(key("port"), "must be between 1 and 65535"));
+        }
+        if let Some(fingerprint) = &network.tls_fingerprint {
+            let hex = fingerprint.replace(':', "");
+            if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid(
+                    key("tls_fingerprint"),
+                    "must be a SHA-256 fingerprint of 64 hex digits",
+                ));
+            }
+        }
+        if network.sasl.is_some() && !file.connection.capabilities.iter().any(|c| c == "sasl") {
+            return Err(invalid(
+                key("sasl"),
+                "needs `sasl` in connection.capabilities",
+            ));
+        }
+        if let Some(sasl) = &network.sasl {
+            let has_password = sasl.password_file.is_some() || sasl.password_env.is_some();
+            match sasl.mechanism {
+                MechanismName::Plain => {
+                    check_password(&key("sasl"), &sasl.password_file, &sasl.password_env)?
+                }
+                MechanismName::External if has_password => {
+                    return Err(invalid(
+                        key("sasl"),
+                        "EXTERNAL uses the TLS client certificate instead of a password",
+                    ));
+                }
+                MechanismName::External if !network.tls || network.tls_client_cert.is_none() => {
+                    return Err(invalid(
+                        key("sasl"),
+                        "EXTERNAL requires tls and tls_client_cert",
+                    ));
+                }
+                MechanismName::External => {}
+            }
+        }
+        if let Some(nickserv) = &network.nickserv {
+            check_password(
+                &key("nickserv"),
+                &nickserv.password_file,
+                &nickserv.password_env,
+            )?;
+        }
+        if let Some(pass) = &network.pass {
+            check_password(&key("pass"), &pass.password_file, &pass.password_env)?;
+        }
+        for (j, channel) in network.channels.iter().enumerate() {
+            let key = |field: &str| format!("networks[{}].channels[{}].{}", i, j, field);
+            check_channel(&key("name"), &channel.name)?;
+            if let Some(k) = &channel.key {
+                if k.is_empty() || k.contains([' ', ',', '\r', '\n', '\0']) {
+                    return Err(invalid(key("key"), "must be a single word without ','"));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// a channel name has to start with one of the usual channel types, since which ones the
+// server uses isn't known until connecting
+fn check_channel(key: &str, name: &str) -> Result<(), ConfigErr> {
+    if !name.starts_with(['#', '&', '+', '!'])
+        || name.contains([' ', ',', '\x07', '\r', '\n', '\0'])
+    {
+        return Err(invalid(key, format!("`{}` isn't a channel name", name)));
+    }
+    Ok(())
+}
+
+// a password has to come from exactly one place
+fn check_password(
+    key: &str,
+    file: &Option<PathBuf>,
+    env: &Option<String>,
+) -> Result<(), ConfigErr> {
+    match (file, env) {
+        (Some(_), Some(_)) => Err(invalid(
+            key,
+            "set only one of `password_file` and `password_env`",
+        )),
+        (None, None) => Err(invalid(key, "needs `password_file` or `password_env`")),
+        _ => Ok(()),
+    }
+}
+
+// reads a password that `check_password` has made sure comes from exactly one place
+fn load_password(
+    key: &str,
+    file: Option<PathBuf>,
+    env: Option<String>,
+) -> Result<Secret, ConfigErr> {
+    let secret = match (file, env) {
+        (Some(path), _) => Secret::from_file(&path).map_err(|e| {
+            invalid(
+                format!("{}.password_file", key),
+                format!("couldn't read {}: {}", path.display(), e),
+            )
+        })?,
+        (None, Some(name)) => Secret::from_env(&name)
+            .map_err(|e| invalid(format!("{}.password_env", key), format!("${}: {}", name, e)))?,
+        (None, None) => unreachable!("checked by check_password"),
+    };
+    if secret.expose().is_empty() {
+        return Err(invalid(key, "the password is empty"));
+    }
+    Ok(secret)
+}
+
+// nicks can't contain spaces or anything that means something else in a target or mask,
+// and can't start with a digit or anything that starts a channel
+fn check_nick(key: &str, nick: &str) -> Result<(), ConfigErr> {
+    let bad_char = nick
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control() || ",*?!@.:".contains(c));
+    let bad_start = nick.starts_with(|c: char| c.is_ascii_digit() || "-#&+$~%".contains(c));
+    if nick.is_empty() || bad_char || bad_start {
+        return Err(invalid(key, format!("`{}` isn't a valid nick", nick)));
+    }
+    Ok(())
+}
+
+impl File {
+    fn into_config(self, network: Option<&str>) -> Result<Config, ConfigErr> {
+        let id = self.identity;
+        let conn = self.connection;
+        let (i, network) = match network {
+            Some(name) => self
+                .networks
+                .into_iter()
+                .enumerate()
+                .find(|(_, n)| n.name == name)
+                .ok_or_else(|| invalid("networks", format!("no network is named `{}`", name)))?,
+            // validation made sure there's at least one
+            None => self.networks.into_iter().enumerate().next().unwrap(),
+        };
+        // only the passwords for the network in use are read
+        let key = |field: &str| format!("networks[{}].{}", i, field);
+        let sasl = match network.sasl {
+            Some(sasl) if sasl.mechanism == MechanismName::External => Some(Mechanism::External),
+            Some(sasl) => Some(Mechanism::Plain {
+                username: sasl.username.unwrap_or_else(|| id.nick.clone()),
+                password: load_password(&key("sasl"), sasl.password_file, sasl.password_env)?,
+            }),
+            None => None,
+        };
+        let nickserv = match network.nickserv {
+            Some(ns) => Some(NickServ {
+                account: ns.account,
+                password: load_password(&key("nickserv"), ns.password_file, ns.password_env)?,
+            }),
+            None => None,
+        };
+        let pass = match network.pass {
+            Some(pass) => Some(load_password(
+                &key("pass"),
+                pass.password_file,
+                pass.password_env,
+            )?),
+            None => None,
+        };
+        Ok(Config {
+            username: id.username.unwrap_or_else(|| id.nick.clone()),
+            my_nick: id.nick,
+            alt_nicks: id.alt_nicks,
+            realname: id.realname,
+            network: Network {
+                port: network
+                    .port
+                    .unwrap_or(if network.tls { 6697 } else { 6667 }),
+                host: network.host,
+                ssl: network.tls,
+                tls: tls::Options {
+                    fingerprint: network.tls_fingerprint,
+                    accept_invalid_certs: network.tls_accept_invalid_certs,
+                    client_cert: network.tls_client_cert,
+                    client_key: network.tls_client_key,
+                },
+                channels: network
+                    .channels
+                    .into_iter()
+                    .map(|c| Channel {
+                        name: c.name,
+                        key: c.key,
+                    })
+                    .collect(),
+                encoding: Encoding {
+                    fallback: network.fallback_encoding.into(),
+                    outgoing: network.encoding.into(),
+                },
+            },
+            capabilities: conn.capabilities,
+            sasl,
+            nickserv,
+            pass,
+            reconnect: backoff::Backoff {
+                initial: Duration::from_millis(conn.reconnect.initial_ms),
+                max: Duration::from_millis(conn.reconnect.max_ms),
+            },
+            keepalive: keepalive::Keepalive {
+                idle: Duration::from_millis(conn.keepalive.idle_ms),
+                timeout: Duration::from_millis(conn.keepalive.timeout_ms),
+            },
+            flood: throttle::FloodControl {
+                burst: conn.flood.burst,
+                interval: Duration::from_millis(conn.flood.interval_ms),
+            },
+            bulk_queue_limit: conn.bulk_queue_limit,
+            splitting: split::Splitting {
+                max_lines: conn.splitting.max_lines,
+                marker: conn.splitting.marker,
+            },
+            triggers: triggers::Settings {
+                heartbeat: triggers::Heartbeat {
+                    enabled: self.triggers.heartbeat.enabled,
+                    command: self.triggers.heartbeat.command,
+                    reply: self.triggers.heartbeat.reply,
+                },
+                on_connect: triggers::OnConnect {
+                    enabled: self.triggers.on_connect.enabled,
+                    delay: Duration::from_millis(self.triggers.on_connect.delay_ms),
+                },
+                reload: triggers::Reload {
+                    enabled: self.triggers.reload.enabled,
+                    command: self.triggers.reload.command,
+                    accounts: self.triggers.reload.accounts,
+                    channels: self.triggers.reload.channels,
+                },
+                modes: triggers::Modes {
+                    enabled: self.triggers.modes.enabled,
+                    accounts: self.triggers.modes.accounts,
+                },
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        parse(text, None).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn parses_the_sample() {
+        let cfg = parse(SAMPLE, None).unwrap();
+        assert_eq!(cfg.my_nick, "ircrab");
+        assert_eq!(cfg.username, "ircrab");
+        assert_eq!(cfg.network.host, "irc.libera.chat");
+        assert_eq!(cfg.network.port, 6697);
+        assert!(cfg.network.ssl);
+        assert_eq!(cfg.network.channels[0].name, "##cwru-testing");
+        assert_eq!(cfg.network.channels[0].key, None);
+        assert_eq!(cfg.network.encoding.fallback, Charset::Cp1252);
+        assert!(cfg.triggers.heartbeat.enabled);
+        assert_eq!(cfg.triggers.on_connect.delay, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn fills_in_defaults() {
+        let text = r##"
+            identity = { nick = "crab", alt_nicks = ["crab2"] }
+            [[networks]]
+            name = "local"
+            host = "localhost"
+            tls = false
+            channels = [{ name = "#a", key = "k" }, { name = "&b" }]
+            [[networks]]
+            name = "other"
+            host = "irc.example.org"
+            "##;
+        let cfg = parse(text, None).unwrap();
+        assert_eq!(cfg.username, "crab");
+        assert_eq!(cfg.alt_nicks, vec!["crab2"]);
+        assert_eq!(cfg.realname, "rust-irc-bot");
+        assert_eq!(cfg.network.host, "localhost");
+        assert_eq!(cfg.network.port, 6667);
+        assert_eq!(cfg.network.channels[0].key.as_deref(), Some("k"));
+        assert_eq!(cfg.network.channels[1].name, "&b");
+        assert_eq!(cfg.network.encoding.outgoing, Charset::Utf8);
+        assert_eq!(cfg.triggers.heartbeat.command, "!ping");
+        assert_eq!(cfg.flood.burst, 5);
+        assert_eq!(cfg.keepalive.idle, Duration::from_secs(120));
+        assert_eq!(cfg.splitting.marker, "(continued)");
+
+        // the sample spells out the defaults
+        let sample = parse(SAMPLE, None).unwrap();
+        assert_eq!(cfg.capabilities, sample.capabilities);
+        assert!(cfg.reconnect == sample.reconnect);
+        assert!(cfg.keepalive == sample.keepalive);
+        assert!(cfg.flood == sample.flood);
+        assert_eq!(cfg.bulk_queue_limit, sample.bulk_queue_limit);
+        assert!(cfg.splitting == sample.splitting);
+
+        let cfg = parse(text, Some("other")).unwrap();
+        assert_eq!(cfg.network.host, "irc.example.org");
+        assert_eq!(cfg.network.port, 6697);
+        assert_eq!(
+            parse(text, Some("nope")).err().unwrap().to_string(),
+            "invalid `networks`: no network is named `nope`"
+        );
+    }
+
+    #[test]
+    fn reads_passwords_from_elsewhere() {
+        let path = std::env::temp_dir().join(format!("ircrab-sasl-{}", std::process::id()));
+        fs::write(&path, "from a file\n").unwrap();
+        std::env::set_var("IRCRAB_TEST_NICKSERV", "from the env");
+        let network = "[identity]\nnick = \"crab\"\n[[networks]]\nname = \"n\"\nhost = \"h\"\n";
+        let cfg = parse(
+            &format!(
+                "{}sasl = {{ password_file = {:?} }}\n\
+                 nickserv = {{ password_env = \"IRCRAB_TEST_NICKSERV\" }}\n\
+                 pass = {{ password_env = \"IRCRAB_TEST_NICKSERV\" }}",
+                network,
+                path.display().to_string()
+            ),
+            None,
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+        match cfg.sasl {
+            Some(Mechanism::Plain { username, password }) => {
+                assert_eq!(username, "crab");
+                assert_eq!(password.expose(), "from a file");
+            }
+            _ => panic!("expected SASL PLAIN"),
+        }
+        let nickserv = cfg.nickserv.unwrap();
+        assert_eq!(nickserv.password.expose(), "from the env");
+        assert_eq!(nickserv.identify().params[1], "IDENTIFY from the env");
+        assert_eq!(cfg.pass.unwrap().expose(), "from the env");
+
+        let e = error(&format!("{}sasl = {{ password = \"hunter2\" }}", network));
+        assert!(e.contains("unknown field `password`"), "{}", e);
+        let e = error(&format!(
+            "{}pass = {{ password_env = \"A\", password_file = \"b\" }}",
+            network
+        ));
+        assert_eq!(
+            e,
+            "invalid `networks[0].pass`: set only one of `password_file` and `password_env`"
+        );
+        let e = error(&format!("{}nickserv = {{ account = \"crab\" }}", network));
+        assert_eq!(
+            e,
+            "invalid `networks[0].nickserv`: needs `password_file` or `password_env`"
+        );
+        let e = error(&format!(
+            "{}sasl = {{ mechanism = \"external\", password_env = \"A\" }}",
+            network
+        ));
+        assert!(
+            e.starts_with("invalid `networks[0].sasl`: EXTERNAL"),
+            "{}",
+            e
+        );
+        let e = error(&format!(
+            "{}pass = {{ password_env = \"IRCRAB_TEST_UNSET\" }}",
+            network
+        ));
+        assert!(
+            e.starts_with("invalid `networks[0].pass.password_env`: $IRCRAB_TEST_UNSET"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn reads_connection_settings() {
+        let network = "[identity]\nnick = \"crab\"\n[[networks]]\nname = \"n\"\nhost = \"h\"\n";
+        let cfg = parse(
+            &format!(
+                "{}[connection]\ncapabilities = [\"batch\"]\nbulk_queue_limit = 5\n\
+                 [connection.flood]\nburst = 10\n\
+                 [connection.splitting]\nmarker = \"...\"",
+                network
+            ),
+            None,
+        )
+        .unwrap();
+        assert_eq!(cfg.capabilities, vec!["batch"]);
+        assert_eq!(cfg.bulk_queue_limit, 5);
+        assert_eq!(cfg.flood.burst, 10);
+        assert_eq!(cfg.flood.interval, Duration::from_secs(2));
+        assert_eq!(cfg.splitting.max_lines, 4);
+        assert_eq!(cfg.splitting.marker, "...");
+
+        let e = error(&format!("{}[connection.flood]\nburst = 0", network));
+        assert_eq!(e, "invalid `connection.flood.burst`: must be at least 1");
+        let e = error(&format!("{}[connection.keepalive]\nidle_ms = 0", network));
+        assert_eq!(
+            e,
+            "invalid `connection.keepalive.idle_ms`: must be more than 0"
+        );
+        let e = error(&format!(
+            "{}[connection.reconnect]\ninitial_ms = 5000\nmax_ms = 1000",
+            network
+        ));
+        assert_eq!(
+            e,
+            "invalid `connection.reconnect.max_ms`: can't be less than initial_ms"
+        );
+        let e = error(&format!(
+            "{}[connection]\ncapabilities = [\"batch\", \"a b\"]",
+            network
+        ));
+        assert_eq!(
+            e,
+            "invalid `connection.capabilities[1]`: `a b` isn't a capability name"
+        );
+        let e = error(&format!(
+            "{}sasl = {{ password_env = \"A\" }}\n[connection]\ncapabilities = []",
+            network
+        ));
+        assert_eq!(
+            e,
+            "invalid `networks[0].sasl`: needs `sasl` in connection.capabilities"
+        );
+    }
+
+    #[test]
+    fn needs_a_client_cert_for_external() {
+        let network = "[identity]\nnick = \"crab\"\n[[networks]]\nname = \"n\"\nhost = \"h\"\n";
+        let sasl = "sasl = { mechanism = \"external\" }\n";
+        let cert = "tls_client_cert = \"client.pem\"\n";
+        let want = "invalid `networks[0].sasl`: EXTERNAL requires tls and tls_client_cert";
+        assert_eq!(error(&format!("{}{}", network, sasl)), want);
+        assert_eq!(
+            error(&format!("{}tls = false\n{}{}", network, cert, sasl)),
+            want
+        );
+        let cfg = parse(&format!("{}{}{}", network, cert, sasl), None).unwrap();
+        assert!(matches!(cfg.sasl, Some(Mechanism::External)));
+    }
+
+    #[test]
+    fn names_the_offending_key() {
+        let network = "[[networks]]\nname = \"n\"\nhost = \"h\"\n";
+        let e = error(&format!("[identity]\nnick = \"1crab\"\n{}", network));
+        assert_eq!(e, "invalid `identity.nick`: `1crab` isn't a valid nick");
+        let e = error(&format!(
+            "[identity]\nnick = \"crab\"\n{}channels = [{{ name = \"#ok\" }}, {{ name = \"cwru\" }}]",
+            network
+        ));
+        assert_eq!(
+            e,
+            "invalid `networks[0].channels[1].name`: `cwru` isn't a channel name"
+        );
+        let e = error(&format!(
+            "[identity]\nnick = \"crab\"\n[triggers.reload]\nchannels = [\"ops\"]\n{}",
+            network
+        ));
+        assert_eq!(
+            e,
+            "invalid `triggers.reload.channels[0]`: `ops` isn't a channel name"
+        );
+        let e = error(&format!(
+            "[identity]\nnick = \"crab\"\n{}{}",
+            network, network
+        ));
+        assert_eq!(
+            e,
+            "invalid `networks[1].name`: `n` is used by more than one network"
+        );
+        let e = error("networks = []\n[identity]\nnick = \"crab\"");
+        assert_eq!(e, "invalid `networks`: at least one network is needed");
+
+        // the parser points at the line, and names the key
+        let e = error(&format!(
+            "[identity]\nnick = \"crab\"\n{}port = \"six\"",
+            network
+        ));
+        assert!(e.contains("line 6"), "{}", e);
+        assert!(e.contains("port"), "{}", e);
+        let e = error(&format!(
+            "[identity]\nnick = \"crab\"\n{}prot = 6697",
+            network
+        ));
+        assert!(e.contains("unknown field `prot`"), "{}", e);
+        let e = error(&format!(
+            "[identity]\nnick = \"crab\"\n{}encoding = \"ebcdic\"",
+            network
+        ));
+        assert!(e.contains("utf-8"), "{}", e);
+        let e = error("[identity]\nnick = \"crab\"");
+        assert!(e.contains("missing field `networks`"), "{}", e);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::irc::{Command, Message};
+use crate::queue::Priority;
+use crate::state::State;
+
+/// Sends a single message once registered, then quits. A channel is joined first,
+/// since most of them don't take messages from outside.
+pub struct Delivery {
+    target: String,
+    text: String,
+    // the channel's key, if it has one
+    key: Option<String>,
+    joining: bool,
+    sent: bool,
+}
+
+impl Delivery {
+    pub fn new(target: &str, text: &str, key: Option<&str>) -> Delivery {
+        Delivery {
+            target: target.to_string(),
+            text: text.to_string(),
+            key: key.map(str::to_string),
+            joining: false,
+            sent: false,
+        }
+    }
+
+    /// Returns true once the message and the QUIT after it have been queued
+    pub fn sent(&self) -> bool {
+        self.sent
+    }
+
+    /// Handles a message the server sent, returning anything that should be sent in response.
+    /// Fails if the server won't let the message through.
+    pub fn handle(
+        &mut self,
+        msg: &Message,
+        state: &State,
+    ) -> Result<Vec<(Message, Priority)>, String> {
+        let casemapping = state.support().casemapping;
+        let is_target = |p: Option<&String>| p.is_some_and(|p| casemapping.same(p, &self.target));
+        match msg.command {
+            // ISUPPORT has all arrived by the end of the MOTD, so we know what a channel looks like
+            Command::RPL_ENDOFMOTD | Command::ERR_NOMOTD if !self.joining && !self.sent => {
+                if !state.support().is_channel(&self.target) {
+                    return Ok(self.send());
+                }
+                self.joining = true;
+                let mut params = vec![self.target.clone()];
+                params.extend(self.key.clone());
+                Ok(vec![(
+                    Message::new(Command::JOIN, params),
+                    Priority::Interactive,
+                )])
+            }
+            Command::JOIN if self.joining && !self.sent && is_target(msg.params.first()) => {
+                let from_me = msg
+                    .source
+                    .as_ref()
+                    .and_then(|s| s.nick())
+                    .is_some_and(|nick| state.is_me(nick));
+                Ok(if from_me { self.send() } else { vec![] })
+            }
+            Command::ERR_NOSUCHNICK
+            | Command::ERR_NOSUCHCHANNEL
+            | Command::ERR_CANNOTSENDTOCHAN
+            | Command::ERR_TOOMANYCHANNELS
+            | Command::ERR_CHANNELISFULL
+            | Command::ERR_INVITEONLYCHAN
+            | Command::ERR_BANNEDFROMCHAN
+            | Command::ERR_BADCHANNELKEY
+                if is_target(msg.params.get(1)) =>
+            {
+                Err(format!(
+                    "couldn't send to {}: {}",
+                    self.target,
+                    msg.params.last().map_or("", |p| p.as_str())
+                ))
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    // the message, and a QUIT that waits behind it. QUIT is critical by default, which
+    // would let it jump ahead
+    fn send(&mut self) -> Vec<(Message, Priority)> {
+        self.sent = true;
+        vec![
+            (
+                Message::new(
+                    Command::PRIVMSG,
+                    vec![self.target.clone(), self.text.clone()],
+                ),
+                Priority::Interactive,
+            ),
+            (Message::new(Command::QUIT, vec![]), Priority::Bulk),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::parse_message;
+
+    fn handle(d: &mut Delivery, state: &State, line: &str) -> Result<Vec<String>, String> {
+        let out = d.handle(&parse_message(line).unwrap(), state)?;
+        Ok(out.iter().map(|(m, _)| m.serialize().unwrap()).collect())
+    }
+
+    #[test]
+    fn joins_channels_first() {
+        let state = State::new("ircrab");
+        let mut d = Delivery::new("#cwru", "deployed", Some("hunter2"));
+        assert_eq!(
+            handle(&mut d, &state, ":srv 376 ircrab :End of /MOTD"),
+            Ok(vec!["JOIN #cwru hunter2\r\n".to_string()])
+        );
+        assert!(!d.sent());
+        assert_eq!(handle(&mut d, &state, ":foo!u@h JOIN #cwru"), Ok(vec![]));
+        assert_eq!(
+            handle(&mut d, &state, ":ircrab!u@h JOIN #CWRU"),
+            Ok(vec![
+                "PRIVMSG #cwru deployed\r\n".to_string(),
+                "QUIT\r\n".to_string()
+            ])
+        );
+        assert!(d.sent());
+        assert_eq!(
+            handle(
+                &mut d,
+                &state,
+                ":srv 404 ircrab #cwru :Cannot send to channel"
+            ),
+            Err("couldn't send to #cwru: Cannot send to channel".to_string())
+        );
+    }
+
+    #[test]
+    fn messages_nicks_directly() {
+        let state = State::new("ircrab");
+        let mut d = Delivery::new("raidan", "deployed", None);
+        let out = d
+            .handle(
+                &parse_message(":srv 422 ircrab :MOTD File is missing").unwrap(),
+                &state,
+            )
+            .unwrap();
+        assert_eq!(out[0].0.params, vec!["raidan", "deployed"]);
+        assert_eq!(
+            out[1],
+            (Message::new(Command::QUIT, vec![]), Priority::Bulk)
+        );
+        assert_eq!(
+            handle(&mut d, &state, ":srv 401 ircrab raidan :No such nick"),
+            Err("couldn't send to raidan: No such nick".to_string())
+        );
+    }
+}
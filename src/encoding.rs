@@ -0,0 +1,156 @@
+use std::borrow::Cow;
+
+/// A character set text can be sent or received in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Charset {
+    Utf8,
+    // ISO-8859-1, where every byte is the code point of the same value
+    Latin1,
+    // Windows-1252, which is Latin-1 with printable characters in place of most C1 controls
+    Cp1252,
+}
+
+/// How to turn a network's bytes into text and back
+#[derive(Clone)]
+pub struct Encoding {
+    // what to decode incoming lines as when they aren't valid UTF-8.
+    // UTF-8 here means decoding them anyway, with replacement characters
+    pub fallback: Charset,
+    // what to encode outgoing lines as
+    pub outgoing: Charset,
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding {
+            fallback: Charset::Cp1252,
+            outgoing: Charset::Utf8,
+        }
+    }
+}
+
+/// The way an incoming line was turned into text
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Decoded {
+    // valid UTF-8, as it should be
+    #[default]
+    Utf8,
+    Latin1,
+    Cp1252,
+    // invalid UTF-8 with the bad sequences replaced by U+FFFD
+    Lossy,
+}
+
+/// Decodes a line as UTF-8 if it's valid, and as the fallback if it isn't
+pub fn decode(bytes: &[u8], fallback: Charset) -> (Cow<'_, str>, Decoded) {
+    if let Ok(s) = std::str::from_utf8(bytes) {
+        return (Cow::Borrowed(s), Decoded::Utf8);
+    }
+    match fallback {
+        Charset::Utf8 => (String::from_utf8_lossy(bytes), Decoded::Lossy),
+        Charset::Latin1 => (
+            Cow::Owned(bytes.iter().map(|&b| b as char).collect()),
+            Decoded::Latin1,
+        ),
+        Charset::Cp1252 => (
+            Cow::Owned(bytes.iter().map(|&b| cp1252_char(b)).collect()),
+            Decoded::Cp1252,
+        ),
+    }
+}
+
+/// Encodes a line for the wire. Characters the charset doesn't have are sent as '?'.
+pub fn encode(s: &str, charset: Charset) -> Cow<'_, [u8]> {
+    match charset {
+        Charset::Utf8 => Cow::Borrowed(s.as_bytes()),
+        Charset::Latin1 => Cow::Owned(s.chars().map(|c| u8::try_from(c).unwrap_or(b'?')).collect()),
+        Charset::Cp1252 => Cow::Owned(s.chars().map(|c| cp1252_byte(c).unwrap_or(b'?')).collect()),
+    }
+}
+
+// What 0x80 to 0x9F mean in CP1252. The five bytes it leaves undefined stay C1 controls,
+// the same as in Latin-1.
+const CP1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}', '\u{8F}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}', '\u{178}',
+];
+
+fn cp1252_char(b: u8) -> char {
+    match b {
+        0x80..=0x9F => CP1252_HIGH[(b - 0x80) as usize],
+        _ => b as char,
+    }
+}
+
+fn cp1252_byte(c: char) -> Option<u8> {
+    if let Some(i) = CP1252_HIGH.iter().position(|&h| h == c) {
+        return Some(0x80 + i as u8);
+    }
+    // anything else in 0x80-0x9F is covered by the table above
+    match u8::try_from(c) {
+        Ok(b) if !(0x80..=0x9F).contains(&b) => Some(b),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_utf8() {
+        for fallback in [Charset::Utf8, Charset::Latin1, Charset::Cp1252] {
+            let (text, decoded) = decode("café 🦀".as_bytes(), fallback);
+            assert_eq!(text, "café 🦀");
+            assert_eq!(decoded, Decoded::Utf8);
+        }
+    }
+
+    #[test]
+    fn falls_back() {
+        let line = b"caf\xe9 \x93quoted\x94";
+        assert_eq!(
+            decode(line, Charset::Latin1),
+            (
+                Cow::Owned("caf\u{e9} \u{93}quoted\u{94}".to_string()),
+                Decoded::Latin1
+            )
+        );
+        assert_eq!(
+            decode(line, Charset::Cp1252),
+            (
+                Cow::Owned("café \u{201C}quoted\u{201D}".to_string()),
+                Decoded::Cp1252
+            )
+        );
+        assert_eq!(
+            decode(line, Charset::Utf8),
+            (
+                Cow::Owned("caf\u{FFFD} \u{FFFD}quoted\u{FFFD}".to_string()),
+                Decoded::Lossy
+            )
+        );
+    }
+
+    #[test]
+    fn encodes() {
+        let text = "café “quoted” 🦀";
+        assert_eq!(encode(text, Charset::Utf8), text.as_bytes());
+        assert_eq!(encode(text, Charset::Latin1), &b"caf\xe9 ?quoted? ?"[..]);
+        assert_eq!(
+            encode(text, Charset::Cp1252),
+            &b"caf\xe9 \x93quoted\x94 ?"[..]
+        );
+        // a C1 control that CP1252 has replaced can't be sent as itself
+        assert_eq!(encode("\u{93}", Charset::Cp1252), &b"?"[..]);
+    }
+
+    #[test]
+    fn cp1252_round_trips() {
+        for b in 0..=255u8 {
+            assert_eq!(cp1252_byte(cp1252_char(b)), Some(b));
+        }
+    }
+}
@@ -1,106 +1,483 @@
+use crate::encoding::Decoded;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fmt;
 
+/// IRCv3 message tags, keyed by tag name (including any vendor or client-only `+` prefix).
+/// A tag sent without a value is stored with an empty value, which the spec treats as equivalent.
+pub type Tags = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Message {
+    pub tags: Tags,
+    pub source: Option<Source>,
     pub command: Command,
     pub params: Vec<String>,
+    // how the line was turned into text, for messages that came from the server
+    pub decoded: Decoded,
 }
 
-pub fn parse_message(s: &str) -> Result<Message, ParseErr> {
-    let trimmed = s.trim_end_matches(|c| c == '\r' || c == '\n').to_string();
-
-    let mut i: i32 = 0;
-    let mut j: i32;
-
-    // there's this whole prefix business, but I dunno what it does, so let's eat it.
-    // TODO(raidancampbell): actually parse it
-    if trimmed.starts_with(':') {
-        i = (trimmed.find(' ').unwrap() + 1) as i32;
-    }
-
-    // next chunk is the command
-    j = i + trimmed.get((i as usize)..).unwrap().find(' ').unwrap_or(0) as i32;
-    let cmd: &str = if j > i {
-        trimmed.get((i as usize)..(j as usize)).unwrap()
-    } else {
-        return Ok(Message {
-            command: Command::from_str(trimmed.get((i as usize)..).unwrap()).unwrap(),
-            params: vec![],
-        });
+impl Message {
+    pub fn new(command: Command, params: Vec<String>) -> Message {
+        Message {
+            tags: Tags::new(),
+            source: None,
+            command,
+            params,
+            decoded: Decoded::Utf8,
+        }
+    }
+
+    /// Attach a tag to this message, e.g. `+reply` with the msgid of the message being answered
+    pub fn with_tag(mut self, key: &str, value: &str) -> Message {
+        self.tags.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Serializes the message into a line ready for the wire, including the trailing CRLF.
+    /// If the line is too long, the final parameter is truncated to fit.
+    pub fn serialize(&self) -> Result<String, SerializeErr> {
+        self.serialize_within(MAX_LINE_LEN)
+    }
+
+    /// Like `serialize`, but for a server whose lines can be `line_len` bytes long, including
+    /// the CRLF, as advertised by ISUPPORT LINELEN
+    pub fn serialize_within(&self, line_len: usize) -> Result<String, SerializeErr> {
+        let max = line_len.saturating_sub(2);
+        let mut out = String::new();
+        if !self.tags.is_empty() {
+            let tags = serialize_tags(&self.tags, |_| true);
+            if tags.len() > MAX_TAGS_LEN {
+                return Err(SerializeErr::TagsTooLong(tags.len()));
+            }
+            out.push('@');
+            out.push_str(&tags);
+            out.push(' ');
+        }
+        // tags have their own limit, so the line length only starts here
+        let start = out.len();
+
+        if let Some(source) = &self.source {
+            out.push(':');
+            out.push_str(&source.to_string());
+            out.push(' ');
+        }
+        out.push_str(&self.command.as_str());
+
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                if param.is_empty()
+                    || param.starts_with(':')
+                    || param.contains([' ', '\r', '\n', '\0'])
+                {
+                    return Err(SerializeErr::InvalidParam(param.clone()));
+                }
+                out.push(' ');
+                out.push_str(param);
+            }
+
+            if last.contains(['\r', '\n', '\0']) {
+                return Err(SerializeErr::InvalidParam(last.clone()));
+            }
+            out.push(' ');
+            let too_long = SerializeErr::TooLong(out.len() - start + last.len() + 2, line_len);
+            let budget = max.checked_sub(out.len() - start).ok_or(too_long.clone())?;
+            // the last parameter can only hold spaces, or be empty, if it's marked as trailing.
+            // that's decided after truncating, since truncating can leave it empty
+            let trailing = |p: &str| p.is_empty() || p.starts_with(':') || p.contains(' ');
+            let mut text = truncate(last, budget);
+            if trailing(text) {
+                text = truncate(last, budget.checked_sub(1).ok_or(too_long)?);
+                out.push(':');
+            }
+            out.push_str(text);
+        }
+
+        if out.len() - start > max {
+            return Err(SerializeErr::TooLong(out.len() - start + 2, line_len));
+        }
+        out.push_str("\r\n");
+        Ok(out)
+    }
+}
+
+/// Checks that a line the parser accepts comes back the same after being serialized and parsed
+/// again, apart from the final parameter being cut short to fit. Shared with the fuzz targets.
+#[cfg(any(test, fuzzing))]
+pub fn check_round_trip(line: &str) {
+    let Ok(msg) = parse_message(line) else {
+        return;
+    };
+    // some things can be parsed but not sent, like a NUL in a parameter
+    let Ok(serialized) = msg.serialize() else {
+        return;
     };
+    let reparsed = parse_message(&serialized).expect("serialized message doesn't parse");
+    assert_eq!(reparsed.tags, msg.tags);
+    assert_eq!(reparsed.source, msg.source);
+    assert_eq!(reparsed.command, msg.command);
+    assert_eq!(reparsed.params.len(), msg.params.len(), "{:?}", serialized);
+    if let Some((last, middle)) = reparsed.params.split_last() {
+        assert_eq!(middle, &msg.params[..middle.len()]);
+        assert!(msg.params[middle.len()].starts_with(last.as_str()));
+    }
+}
+
+// the most bytes allowed in a line, including the CRLF but excluding tags, unless the server
+// advertises otherwise
+const MAX_LINE_LEN: usize = 512;
+// the most bytes of tag data a client may send, excluding the leading '@' and trailing space
+const MAX_TAGS_LEN: usize = 4094;
+
+/// Returns the longest prefix of `s` that fits in `max` bytes without splitting a character
+pub fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
 
-    let command = Command::from_str(cmd);
-    if command.is_err() {
-        return Err(command.err().unwrap());
-    }
-
-    //
-    // 	// Find prefix for trailer. Note that because we need to match the trailing
-    // 	// argument even if it's the only one, we can't skip the space until we've
-    // 	// searched for it.
-    i = trimmed
-        .get((j as usize)..)
-        .unwrap()
-        .find(" :")
-        .map(|x| x as i32)
-        .unwrap_or(-1);
-    //
-    // 	// Skip the space
-    j += 1;
-    if i < 0 {
-        return Ok(Message {
-            command: command.unwrap(),
-            params: trimmed
-                .get((j as usize)..)
-                .unwrap()
-                .to_string()
-                .split(' ')
-                .map(|x| x.to_string())
-                .collect::<Vec<String>>(),
-        });
-    }
-
-    // 	// Compensate for index on substring. Note that we skipped the space after
-    // 	// looking for i, so we need to subtract 1 to account for that.
-    i = i + j - 1;
-    //
-    // 	// Check if we need to parse arguments.
-    let mut params: Vec<String> = vec![];
-    if i > j {
-        params = trimmed
-            .get((j as usize)..(i as usize))
-            .unwrap()
-            .to_string()
-            .split(' ')
-            .map(|x| x.to_string())
-            .collect::<Vec<String>>();
-    }
-    params.push(trimmed.get((i as usize) + 2..).unwrap().to_string());
-
-    Ok(Message {
-        command: command.unwrap(),
-        params,
-    })
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SerializeErr {
+    // a parameter that can't be represented on the wire
+    InvalidParam(String),
+    // the line is too long even with the final parameter dropped entirely: its length, and
+    // the limit
+    TooLong(usize, usize),
+    TagsTooLong(usize),
+}
+
+impl fmt::Display for SerializeErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SerializeErr::InvalidParam(p) => write!(f, "invalid parameter {:?}", p),
+            SerializeErr::TooLong(n, max) => {
+                write!(f, "line is {} bytes, over the limit of {}", n, max)
+            }
+            SerializeErr::TagsTooLong(n) => write!(
+                f,
+                "tags are {} bytes, over the limit of {}",
+                n, MAX_TAGS_LEN
+            ),
+        }
+    }
+}
+
+// Splits tags into keys and still escaped values. Duplicate keys are left for the caller to
+// resolve in favor of the last one.
+fn split_tags(s: &str) -> impl Iterator<Item = (&str, &str)> {
+    s.split(';')
+        .filter(|t| !t.is_empty())
+        .map(|tag| tag.split_once('=').unwrap_or((tag, "")))
+}
+
+fn validate_tags(s: &str) -> Result<(), ParseErr> {
+    // key = [ '+' ] [ vendor '/' ] 1*( letter / digit / '-' ), where the vendor is a hostname
+    let valid = |key: &str| {
+        let key = key.strip_prefix('+').unwrap_or(key);
+        let name = key.rsplit('/').next().unwrap_or("");
+        !name.is_empty()
+            && key
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'/')
+    };
+    if s.is_empty() || !split_tags(s).all(|(key, _)| valid(key)) {
+        return Err(ParseErr::BadTag(s.to_string()));
+    }
+    Ok(())
+}
+
+/// Serializes tags into `key=value;key2` form, without the leading '@'.
+/// Only tags matching `filter` are included.
+pub fn serialize_tags(tags: &Tags, filter: impl Fn(&str) -> bool) -> String {
+    let mut out = String::new();
+    for (key, value) in tags.iter().filter(|(k, _)| filter(k)) {
+        if !out.is_empty() {
+            out.push(';');
+        }
+        out.push_str(key);
+        if !value.is_empty() {
+            out.push('=');
+            out.push_str(&escape_tag_value(value));
+        }
+    }
+    out
+}
+
+fn unescape_tag_value(s: &str) -> Cow<'_, str> {
+    if !s.contains('\\') {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        // an unknown escape drops the backslash, and a trailing lone backslash is dropped entirely
+        match chars.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out.into()
+}
+
+fn escape_tag_value(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            ';' => out.push_str("\\:"),
+            ' ' => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            _ => out.push(c),
+        }
+    }
+    out
 }
 
+/// The origin of a message, as carried in its prefix.
+/// See RFC 2812 section 2.3.1: `prefix = servername / ( nickname [ [ "!" user ] "@" host ] )`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Server(String),
+    User {
+        nick: String,
+        user: Option<String>,
+        host: Option<String>,
+    },
+}
+
+impl Source {
+    pub fn parse(s: &str) -> Source {
+        let (rest, host) = match s.split_once('@') {
+            Some((rest, host)) => (rest, Some(host.to_string())),
+            None => (s, None),
+        };
+        let (nick, user) = match rest.split_once('!') {
+            Some((nick, user)) => (nick, Some(user.to_string())),
+            None => (rest, None),
+        };
+
+        // nicknames can't contain a '.', servernames always do
+        if user.is_none() && host.is_none() && nick.contains('.') {
+            return Source::Server(nick.to_string());
+        }
+        Source::User {
+            nick: nick.to_string(),
+            user,
+            host,
+        }
+    }
+
+    /// The nickname of the sender, if this message came from a user rather than a server
+    pub fn nick(&self) -> Option<&str> {
+        match self {
+            Source::Server(_) => None,
+            Source::User { nick, .. } => Some(nick),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Server(name) => write!(f, "{}", name),
+            Source::User { nick, user, host } => {
+                write!(f, "{}", nick)?;
+                if let Some(user) = user {
+                    write!(f, "!{}", user)?;
+                }
+                if let Some(host) = host {
+                    write!(f, "@{}", host)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn parse_message(s: &str) -> Result<Message, ParseErr> {
+    MessageRef::parse(s).map(Message::from)
+}
+
+/// A message that borrows from the line it was parsed from, so reading one doesn't allocate.
+/// Tag values stay escaped and parameters are split out as they're iterated, which
+/// `Message::from` takes care of when an owned copy is needed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageRef<'a> {
+    // without the leading '@'
+    tags: &'a str,
+    source: Option<&'a str>,
+    pub command: Command,
+    // everything after the command
+    params: &'a str,
+}
+
+impl<'a> MessageRef<'a> {
+    /// Parses a line, with or without its CRLF, in the format of
+    /// `[@tags] [:source] command [params] [:trailing]`.
+    pub fn parse(line: &'a str) -> Result<MessageRef<'a>, ParseErr> {
+        let mut rest = line.trim_end_matches(['\r', '\n']).trim_start_matches(' ');
+        if rest.trim_end_matches(' ').is_empty() {
+            return Err(ParseErr::Empty);
+        }
+
+        // IRCv3 tags come before everything else, including the prefix
+        let mut tags = "";
+        if let Some(after) = rest.strip_prefix('@') {
+            let (t, after) = after.split_once(' ').ok_or(ParseErr::MissingCommand)?;
+            validate_tags(t)?;
+            tags = t;
+            rest = after.trim_start_matches(' ');
+        }
+
+        // the prefix tells us who sent the message
+        let mut source = None;
+        if let Some(after) = rest.strip_prefix(':') {
+            let (s, after) = after.split_once(' ').unwrap_or((after, ""));
+            if !valid_source(s) {
+                return Err(ParseErr::InvalidPrefix(s.to_string()));
+            }
+            source = Some(s);
+            rest = after.trim_start_matches(' ');
+        }
+
+        let (command, params) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.is_empty() {
+            return Err(ParseErr::MissingCommand);
+        }
+        Ok(MessageRef {
+            tags,
+            source,
+            command: Command::from_str(command)?,
+            params,
+        })
+    }
+
+    /// Each tag's key and unescaped value, in the order they were sent
+    pub fn tags(&self) -> impl Iterator<Item = (&'a str, Cow<'a, str>)> {
+        split_tags(self.tags).map(|(key, value)| (key, unescape_tag_value(value)))
+    }
+
+    pub fn params(&self) -> Params<'a> {
+        Params { rest: self.params }
+    }
+
+    /// The unescaped value of a tag. If it was sent more than once, the last value counts
+    pub fn tag(&self, key: &str) -> Option<Cow<'a, str>> {
+        self.tags()
+            .filter(|(k, _)| *k == key)
+            .last()
+            .map(|(_, v)| v)
+    }
+
+    /// The nickname of the sender, if this message came from a user rather than a server.
+    /// Which is which is worked out the same way as `Source::parse`.
+    pub fn nick(&self) -> Option<&'a str> {
+        let source = self.source?;
+        let nick = source.split(['!', '@']).next().unwrap_or(source);
+        if nick.len() == source.len() && nick.contains('.') {
+            return None;
+        }
+        Some(nick)
+    }
+}
+
+impl From<MessageRef<'_>> for Message {
+    fn from(msg: MessageRef<'_>) -> Message {
+        Message {
+            tags: msg
+                .tags()
+                .map(|(key, value)| (key.to_string(), value.into_owned()))
+                .collect(),
+            source: msg.source.map(Source::parse),
+            params: msg.params().map(|p| p.to_string()).collect(),
+            command: msg.command,
+            decoded: Decoded::Utf8,
+        }
+    }
+}
+
+/// The parameters of a `MessageRef`, with the leading ':' removed from the trailing one
 #[derive(Debug, Clone)]
-pub struct ParseErr {
-    s: String,
+pub struct Params<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Params<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let rest = self.rest.trim_start_matches(' ');
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+        // the trailing parameter is the rest of the line, spaces and all
+        if let Some(trailing) = rest.strip_prefix(':') {
+            self.rest = "";
+            return Some(trailing);
+        }
+        let (param, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        self.rest = rest;
+        Some(param)
+    }
+}
+
+// A prefix needs a nick or server name, and any user or host it has can't be empty
+fn valid_source(s: &str) -> bool {
+    let (rest, host) = match s.split_once('@') {
+        Some((rest, host)) => (rest, Some(host)),
+        None => (s, None),
+    };
+    let (nick, user) = match rest.split_once('!') {
+        Some((nick, user)) => (nick, Some(user)),
+        None => (rest, None),
+    };
+    !nick.is_empty() && user != Some("") && host != Some("")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErr {
+    // nothing but whitespace
+    Empty,
+    // tags or a prefix that aren't followed by a command
+    MissingCommand,
+    BadTag(String),
+    InvalidPrefix(String),
+    // neither letters nor a three digit numeric
+    InvalidCommand(String),
 }
 
 // Generation of an error is completely separate from how it is displayed.
 // There's no need to be concerned about cluttering complex logic with the display style.
-//
-// Note that we don't store any extra info about the errors. This means we can't state
-// which string failed to parse without modifying our types to carry that information.
 impl fmt::Display for ParseErr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "unable to parse string '{}' into IRC message", self.s)
+        match self {
+            ParseErr::Empty => write!(f, "empty line"),
+            ParseErr::MissingCommand => write!(f, "message has no command"),
+            ParseErr::BadTag(tags) => write!(f, "invalid tags '{}'", tags),
+            ParseErr::InvalidPrefix(prefix) => write!(f, "invalid prefix '{}'", prefix),
+            ParseErr::InvalidCommand(command) => write!(f, "invalid command '{}'", command),
+        }
     }
 }
 
 #[allow(clippy::upper_case_acronyms)]
 #[allow(non_camel_case_types)]
-#[derive(PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
     PASS,
     NICK,
@@ -149,6 +526,23 @@ pub enum Command {
     ISON,
     SERVER,
     NJOIN,
+    CAP,
+    AUTHENTICATE,
+    ACCOUNT,
+    CHGHOST,
+    SETNAME,
+    TAGMSG,
+    BATCH,
+    ACK,
+    FAIL,
+    WARN,
+    NOTE,
+    MONITOR,
+    CHATHISTORY,
+    KNOCK,
+    SILENCE,
+    WEBIRC,
+    STARTTLS,
     RPL_WELCOME,
     RPL_YOURHOST,
     RPL_CREATED,
@@ -287,10 +681,51 @@ pub enum Command {
     ERR_NOOPERHOST,
     ERR_UMODEUNKNOWNFLAG,
     ERR_USERSDONTMATCH,
+    RPL_LOGGEDIN,
+    RPL_LOGGEDOUT,
+    ERR_NICKLOCKED,
+    RPL_SASLSUCCESS,
+    ERR_SASLFAIL,
+    ERR_SASLTOOLONG,
+    ERR_SASLABORTED,
+    ERR_SASLALREADY,
+    RPL_SASLMECHS,
+    RPL_YOURID,
+    RPL_STATSCONN,
+    RPL_LOCALUSERS,
+    RPL_GLOBALUSERS,
+    RPL_WHOISCERTFP,
+    RPL_WHOISREGNICK,
+    RPL_WHOISSPECIAL,
+    RPL_CREATIONTIME,
+    RPL_WHOISACCOUNT,
+    RPL_TOPICWHOTIME,
+    RPL_WHOISBOT,
+    RPL_WHOISACTUALLY,
+    RPL_WHOSPCRPL,
+    RPL_WHOISHOST,
+    RPL_WHOISMODES,
+    RPL_HOSTHIDDEN,
+    ERR_INPUTTOOLONG,
+    ERR_INVALIDKEY,
+    RPL_STARTTLS,
+    RPL_WHOISSECURE,
+    ERR_STARTTLS,
+    ERR_INVALIDMODEPARAM,
+    ERR_NOPRIVS,
+    RPL_MONONLINE,
+    RPL_MONOFFLINE,
+    RPL_MONLIST,
+    RPL_ENDOFMONLIST,
+    ERR_MONLISTFULL,
+    // a numeric reply without a variant of its own
+    Numeric(u16),
+    // a command without a variant of its own
+    Other(String),
 }
 
 impl Command {
-    pub fn as_str(&self) -> &'static str {
+    pub fn as_str(&self) -> Cow<'_, str> {
         match self {
             Command::PASS => "PASS",
             Command::NICK => "NICK",
@@ -339,6 +774,23 @@ impl Command {
             Command::ISON => "ISON",
             Command::SERVER => "SERVER",
             Command::NJOIN => "NJOIN",
+            Command::CAP => "CAP",
+            Command::AUTHENTICATE => "AUTHENTICATE",
+            Command::ACCOUNT => "ACCOUNT",
+            Command::CHGHOST => "CHGHOST",
+            Command::SETNAME => "SETNAME",
+            Command::TAGMSG => "TAGMSG",
+            Command::BATCH => "BATCH",
+            Command::ACK => "ACK",
+            Command::FAIL => "FAIL",
+            Command::WARN => "WARN",
+            Command::NOTE => "NOTE",
+            Command::MONITOR => "MONITOR",
+            Command::CHATHISTORY => "CHATHISTORY",
+            Command::KNOCK => "KNOCK",
+            Command::SILENCE => "SILENCE",
+            Command::WEBIRC => "WEBIRC",
+            Command::STARTTLS => "STARTTLS",
             Command::RPL_WELCOME => "001",
             Command::RPL_YOURHOST => "002",
             Command::RPL_CREATED => "003",
@@ -477,7 +929,47 @@ impl Command {
             Command::ERR_NOOPERHOST => "491",
             Command::ERR_UMODEUNKNOWNFLAG => "501",
             Command::ERR_USERSDONTMATCH => "502",
+            Command::RPL_LOGGEDIN => "900",
+            Command::RPL_LOGGEDOUT => "901",
+            Command::ERR_NICKLOCKED => "902",
+            Command::RPL_SASLSUCCESS => "903",
+            Command::ERR_SASLFAIL => "904",
+            Command::ERR_SASLTOOLONG => "905",
+            Command::ERR_SASLABORTED => "906",
+            Command::ERR_SASLALREADY => "907",
+            Command::RPL_SASLMECHS => "908",
+            Command::RPL_YOURID => "042",
+            Command::RPL_STATSCONN => "250",
+            Command::RPL_LOCALUSERS => "265",
+            Command::RPL_GLOBALUSERS => "266",
+            Command::RPL_WHOISCERTFP => "276",
+            Command::RPL_WHOISREGNICK => "307",
+            Command::RPL_WHOISSPECIAL => "320",
+            Command::RPL_CREATIONTIME => "329",
+            Command::RPL_WHOISACCOUNT => "330",
+            Command::RPL_TOPICWHOTIME => "333",
+            Command::RPL_WHOISBOT => "335",
+            Command::RPL_WHOISACTUALLY => "338",
+            Command::RPL_WHOSPCRPL => "354",
+            Command::RPL_WHOISHOST => "378",
+            Command::RPL_WHOISMODES => "379",
+            Command::RPL_HOSTHIDDEN => "396",
+            Command::ERR_INPUTTOOLONG => "417",
+            Command::ERR_INVALIDKEY => "525",
+            Command::RPL_STARTTLS => "670",
+            Command::RPL_WHOISSECURE => "671",
+            Command::ERR_STARTTLS => "691",
+            Command::ERR_INVALIDMODEPARAM => "696",
+            Command::ERR_NOPRIVS => "723",
+            Command::RPL_MONONLINE => "730",
+            Command::RPL_MONOFFLINE => "731",
+            Command::RPL_MONLIST => "732",
+            Command::RPL_ENDOFMONLIST => "733",
+            Command::ERR_MONLISTFULL => "734",
+            Command::Numeric(n) => return Cow::Owned(format!("{:03}", n)),
+            Command::Other(command) => command,
         }
+        .into()
     }
 
     fn from_str(s: &str) -> Result<Self, ParseErr> {
@@ -529,6 +1021,23 @@ impl Command {
             "ISON" => Ok(Command::ISON),
             "SERVER" => Ok(Command::SERVER),
             "NJOIN" => Ok(Command::NJOIN),
+            "CAP" => Ok(Command::CAP),
+            "AUTHENTICATE" => Ok(Command::AUTHENTICATE),
+            "ACCOUNT" => Ok(Command::ACCOUNT),
+            "CHGHOST" => Ok(Command::CHGHOST),
+            "SETNAME" => Ok(Command::SETNAME),
+            "TAGMSG" => Ok(Command::TAGMSG),
+            "BATCH" => Ok(Command::BATCH),
+            "ACK" => Ok(Command::ACK),
+            "FAIL" => Ok(Command::FAIL),
+            "WARN" => Ok(Command::WARN),
+            "NOTE" => Ok(Command::NOTE),
+            "MONITOR" => Ok(Command::MONITOR),
+            "CHATHISTORY" => Ok(Command::CHATHISTORY),
+            "KNOCK" => Ok(Command::KNOCK),
+            "SILENCE" => Ok(Command::SILENCE),
+            "WEBIRC" => Ok(Command::WEBIRC),
+            "STARTTLS" => Ok(Command::STARTTLS),
             "001" => Ok(Command::RPL_WELCOME),
             "002" => Ok(Command::RPL_YOURHOST),
             "003" => Ok(Command::RPL_CREATED),
@@ -667,7 +1176,51 @@ impl Command {
             "491" => Ok(Command::ERR_NOOPERHOST),
             "501" => Ok(Command::ERR_UMODEUNKNOWNFLAG),
             "502" => Ok(Command::ERR_USERSDONTMATCH),
-            _ => Err(ParseErr { s: s.to_string() }),
+            "900" => Ok(Command::RPL_LOGGEDIN),
+            "901" => Ok(Command::RPL_LOGGEDOUT),
+            "902" => Ok(Command::ERR_NICKLOCKED),
+            "903" => Ok(Command::RPL_SASLSUCCESS),
+            "904" => Ok(Command::ERR_SASLFAIL),
+            "905" => Ok(Command::ERR_SASLTOOLONG),
+            "906" => Ok(Command::ERR_SASLABORTED),
+            "907" => Ok(Command::ERR_SASLALREADY),
+            "908" => Ok(Command::RPL_SASLMECHS),
+            "042" => Ok(Command::RPL_YOURID),
+            "250" => Ok(Command::RPL_STATSCONN),
+            "265" => Ok(Command::RPL_LOCALUSERS),
+            "266" => Ok(Command::RPL_GLOBALUSERS),
+            "276" => Ok(Command::RPL_WHOISCERTFP),
+            "307" => Ok(Command::RPL_WHOISREGNICK),
+            "320" => Ok(Command::RPL_WHOISSPECIAL),
+            "329" => Ok(Command::RPL_CREATIONTIME),
+            "330" => Ok(Command::RPL_WHOISACCOUNT),
+            "333" => Ok(Command::RPL_TOPICWHOTIME),
+            "335" => Ok(Command::RPL_WHOISBOT),
+            "338" => Ok(Command::RPL_WHOISACTUALLY),
+            "354" => Ok(Command::RPL_WHOSPCRPL),
+            "378" => Ok(Command::RPL_WHOISHOST),
+            "379" => Ok(Command::RPL_WHOISMODES),
+            "396" => Ok(Command::RPL_HOSTHIDDEN),
+            "417" => Ok(Command::ERR_INPUTTOOLONG),
+            "525" => Ok(Command::ERR_INVALIDKEY),
+            "670" => Ok(Command::RPL_STARTTLS),
+            "671" => Ok(Command::RPL_WHOISSECURE),
+            "691" => Ok(Command::ERR_STARTTLS),
+            "696" => Ok(Command::ERR_INVALIDMODEPARAM),
+            "723" => Ok(Command::ERR_NOPRIVS),
+            "730" => Ok(Command::RPL_MONONLINE),
+            "731" => Ok(Command::RPL_MONOFFLINE),
+            "732" => Ok(Command::RPL_MONLIST),
+            "733" => Ok(Command::RPL_ENDOFMONLIST),
+            "734" => Ok(Command::ERR_MONLISTFULL),
+            // RFC 2812 section 2.3.1: command = 1*letter / 3digit
+            _ if s.len() == 3 && s.bytes().all(|b| b.is_ascii_digit()) => {
+                Ok(Command::Numeric(s.parse().unwrap()))
+            }
+            _ if !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphabetic()) => {
+                Ok(Command::Other(s.to_string()))
+            }
+            _ => Err(ParseErr::InvalidCommand(s.to_string())),
         }
     }
 }
@@ -675,6 +1228,8 @@ impl Command {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
+    use std::path::Path;
 
     #[test]
     fn parses() {
@@ -684,4 +1239,461 @@ mod tests {
         assert!(msg.command == Command::PRIVMSG);
         assert_eq!(msg.params, vec!["#qux", "!ping"]);
     }
+
+    #[test]
+    fn parses_user_source() {
+        let msg = parse_message(":foo!~bar@baz.com PRIVMSG #qux :!ping").unwrap();
+        let source = msg.source.unwrap();
+        assert_eq!(
+            source,
+            Source::User {
+                nick: "foo".to_string(),
+                user: Some("~bar".to_string()),
+                host: Some("baz.com".to_string()),
+            }
+        );
+        assert_eq!(source.nick(), Some("foo"));
+        assert_eq!(source.to_string(), "foo!~bar@baz.com");
+    }
+
+    #[test]
+    fn parses_server_source() {
+        let msg = parse_message(":tantalum.libera.chat 001 ircrab :Welcome").unwrap();
+        let source = msg.source.unwrap();
+        assert_eq!(source, Source::Server("tantalum.libera.chat".to_string()));
+        assert_eq!(source.nick(), None);
+        assert_eq!(source.to_string(), "tantalum.libera.chat");
+    }
+
+    #[test]
+    fn source_round_trips() {
+        for prefix in [
+            "foo",
+            "foo@baz.com",
+            "foo!~bar@baz.com",
+            "foo!bar@2001:db8::1",
+            "irc.example.org",
+        ] {
+            let msg = parse_message(&format!(":{} PING :x", prefix)).unwrap();
+            assert_eq!(msg.source.unwrap().to_string(), prefix);
+        }
+    }
+
+    #[test]
+    fn parses_tags() {
+        let msg = parse_message(
+            "@time=2022-11-26T20:00:00.000Z;account=foo;+typing;msgid=a\\sb\\:c :foo!~bar@baz.com PRIVMSG #qux :!ping",
+        )
+        .unwrap();
+        assert_eq!(msg.tags["time"], "2022-11-26T20:00:00.000Z");
+        assert_eq!(msg.tags["account"], "foo");
+        assert_eq!(msg.tags["+typing"], "");
+        assert_eq!(msg.tags["msgid"], "a b;c");
+        assert_eq!(msg.source.unwrap().nick(), Some("foo"));
+        assert!(msg.command == Command::PRIVMSG);
+        assert_eq!(msg.params, vec!["#qux", "!ping"]);
+    }
+
+    #[test]
+    fn unescapes_tag_values() {
+        assert_eq!(
+            unescape_tag_value("a\\:b\\sc\\\\d\\re\\nf"),
+            "a;b c\\d\re\nf"
+        );
+        assert_eq!(unescape_tag_value("\\b"), "b");
+        assert_eq!(unescape_tag_value("trailing\\"), "trailing");
+    }
+
+    #[test]
+    fn tags_round_trip() {
+        let mut tags = Tags::new();
+        tags.insert("+reply".to_string(), "abc;def ghi\\\r\n".to_string());
+        tags.insert("+typing".to_string(), "active".to_string());
+        tags.insert("time".to_string(), "".to_string());
+        let serialized = serialize_tags(&tags, |_| true);
+        assert_eq!(
+            serialized,
+            "+reply=abc\\:def\\sghi\\\\\\r\\n;+typing=active;time"
+        );
+        let msg = parse_message(&format!("@{} TAGMSG", serialized)).unwrap();
+        assert_eq!(msg.tags, tags);
+    }
+
+    #[test]
+    fn serializes_client_only_tags() {
+        let msg = Message::new(Command::PRIVMSG, vec![])
+            .with_tag("+reply", "123")
+            .with_tag("msgid", "456");
+        assert_eq!(
+            serialize_tags(&msg.tags, |k| k.starts_with('+')),
+            "+reply=123"
+        );
+    }
+
+    #[test]
+    fn serializes_trailing_params() {
+        let msg = Message::new(
+            Command::PRIVMSG,
+            vec!["#qux".to_string(), "hello world".to_string()],
+        );
+        assert_eq!(msg.serialize().unwrap(), "PRIVMSG #qux :hello world\r\n");
+
+        let msg = Message::new(
+            Command::PRIVMSG,
+            vec!["#qux".to_string(), "pong!".to_string()],
+        );
+        assert_eq!(msg.serialize().unwrap(), "PRIVMSG #qux pong!\r\n");
+
+        let msg = Message::new(Command::PRIVMSG, vec!["#qux".to_string(), ":)".to_string()]);
+        assert_eq!(msg.serialize().unwrap(), "PRIVMSG #qux ::)\r\n");
+
+        let msg = Message::new(Command::TOPIC, vec!["#qux".to_string(), "".to_string()]);
+        assert_eq!(msg.serialize().unwrap(), "TOPIC #qux :\r\n");
+
+        let msg = Message::new(Command::QUIT, vec![]);
+        assert_eq!(msg.serialize().unwrap(), "QUIT\r\n");
+    }
+
+    #[test]
+    fn serializes_tags_and_source() {
+        let mut msg = Message::new(Command::PRIVMSG, vec!["#qux".to_string(), "hi".to_string()])
+            .with_tag("+reply", "a b");
+        msg.source = Some(Source::Server("irc.example.org".to_string()));
+        assert_eq!(
+            msg.serialize().unwrap(),
+            "@+reply=a\\sb :irc.example.org PRIVMSG #qux hi\r\n"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_params() {
+        for middle in ["", "two words", ":colon", "line\nbreak"] {
+            let msg = Message::new(Command::PRIVMSG, vec![middle.to_string(), "hi".to_string()]);
+            assert_eq!(
+                msg.serialize().unwrap_err(),
+                SerializeErr::InvalidParam(middle.to_string())
+            );
+        }
+        let msg = Message::new(
+            Command::PRIVMSG,
+            vec!["#qux".to_string(), "injected\r\nQUIT".to_string()],
+        );
+        assert!(msg.serialize().is_err());
+    }
+
+    #[test]
+    fn truncates_on_char_boundaries() {
+        // 'é' is two bytes, so 510 bytes of budget can't end cleanly
+        let text = "é".repeat(300);
+        let msg = Message::new(Command::PRIVMSG, vec!["#qux".to_string(), text.clone()]);
+        let line = msg.serialize().unwrap();
+        assert!(line.len() <= 512);
+        assert!(line.ends_with("é\r\n"));
+        let sent = parse_message(&line).unwrap();
+        assert!(text.starts_with(&sent.params[1]));
+
+        // tags don't eat into the budget
+        let tagged = Message::new(Command::PRIVMSG, vec!["#qux".to_string(), text])
+            .with_tag("+reply", &"x".repeat(100));
+        let tagged_line = tagged.serialize().unwrap();
+        assert_eq!(tagged_line.len() - line.len(), "@+reply= ".len() + 100);
+    }
+
+    #[test]
+    fn rejects_overlong_lines() {
+        let msg = Message::new(Command::JOIN, vec!["#".repeat(600)]);
+        assert!(matches!(msg.serialize(), Ok(line) if line.len() == 512));
+        let msg = Message::new(Command::MODE, vec!["#".repeat(600), "+o".to_string()]);
+        assert!(matches!(msg.serialize(), Err(SerializeErr::TooLong(..))));
+        let msg =
+            Message::new(Command::PING, vec!["x".to_string()]).with_tag("+x", &"y".repeat(5000));
+        assert!(matches!(msg.serialize(), Err(SerializeErr::TagsTooLong(_))));
+    }
+
+    #[test]
+    fn fits_the_advertised_line_length() {
+        let msg = Message::new(Command::PRIVMSG, vec!["#qux".to_string(), "x".repeat(1000)]);
+        assert_eq!(
+            msg.serialize_within(1024).unwrap().len(),
+            1000 + "PRIVMSG #qux \r\n".len()
+        );
+        assert_eq!(msg.serialize_within(800).unwrap().len(), 800);
+        let msg = Message::new(Command::MODE, vec!["#".repeat(600), "+o".to_string()]);
+        assert!(msg.serialize_within(1024).is_ok());
+        assert_eq!(
+            msg.serialize_within(600).unwrap_err().to_string(),
+            "line is 610 bytes, over the limit of 600"
+        );
+    }
+
+    #[test]
+    fn truncates_strings() {
+        assert_eq!(truncate("hello", 10), "hello");
+        assert_eq!(truncate("hello", 3), "hel");
+        assert_eq!(truncate("héllo", 2), "h");
+        assert_eq!(truncate("héllo", 3), "hé");
+        assert_eq!(truncate("🦀", 3), "");
+    }
+
+    #[test]
+    fn parses_unknown_commands() {
+        let msg = parse_message(":irc.example.org 042 ircrab 9XAAAAAAA :your unique ID").unwrap();
+        assert_eq!(msg.command, Command::RPL_YOURID);
+        let msg = parse_message(":irc.example.org 999 ircrab :something vendor specific").unwrap();
+        assert_eq!(msg.command, Command::Numeric(999));
+        assert_eq!(
+            msg.serialize().unwrap(),
+            ":irc.example.org 999 ircrab :something vendor specific\r\n"
+        );
+        let msg = parse_message(":nick!user@host FROBNICATE #chan").unwrap();
+        assert_eq!(msg.command, Command::Other("FROBNICATE".to_string()));
+        assert_eq!(msg.params, vec!["#chan"]);
+        let msg = parse_message(":nick!user@host ACCOUNT accountname").unwrap();
+        assert_eq!(msg.command, Command::ACCOUNT);
+        let msg = parse_message(":irc.example.org 396 ircrab user/ircrab :is now your hidden host")
+            .unwrap();
+        assert_eq!(msg.command, Command::RPL_HOSTHIDDEN);
+
+        assert_eq!(Command::Numeric(7).as_str(), "007");
+        assert!(parse_message(":irc.example.org 12345 ircrab").is_err());
+        assert!(parse_message(":irc.example.org PRIV-MSG ircrab").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for (line, err) in [
+            ("", ParseErr::Empty),
+            ("  \r\n", ParseErr::Empty),
+            (":irc.example.org", ParseErr::MissingCommand),
+            (":irc.example.org   ", ParseErr::MissingCommand),
+            ("@time=now", ParseErr::MissingCommand),
+            ("@ PING x", ParseErr::BadTag("".to_string())),
+            ("@=x PING x", ParseErr::BadTag("=x".to_string())),
+            ("@a;+ PING x", ParseErr::BadTag("a;+".to_string())),
+            (
+                "@foo b4r PING x",
+                ParseErr::InvalidCommand("b4r".to_string()),
+            ),
+            (": PING x", ParseErr::InvalidPrefix("".to_string())),
+            (
+                ":nick! PING x",
+                ParseErr::InvalidPrefix("nick!".to_string()),
+            ),
+            (
+                ":!user@host PING x",
+                ParseErr::InvalidPrefix("!user@host".to_string()),
+            ),
+            (
+                ":nick@ PING x",
+                ParseErr::InvalidPrefix("nick@".to_string()),
+            ),
+            (
+                "PRIV-MSG #qux",
+                ParseErr::InvalidCommand("PRIV-MSG".to_string()),
+            ),
+        ] {
+            assert_eq!(parse_message(line).unwrap_err(), err, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn tolerates_extra_spaces() {
+        let msg = parse_message("@a=b  :nick!user@host   PRIVMSG  #qux   :hi  there \r\n").unwrap();
+        assert_eq!(msg.tags["a"], "b");
+        assert_eq!(msg.source.unwrap().nick(), Some("nick"));
+        assert_eq!(msg.params, vec!["#qux", "hi  there "]);
+
+        let msg = parse_message("MODE #qux +o nick ").unwrap();
+        assert_eq!(msg.params, vec!["#qux", "+o", "nick"]);
+        let msg = parse_message("PRIVMSG #qux :").unwrap();
+        assert_eq!(msg.params, vec!["#qux", ""]);
+        let msg = parse_message("PRIVMSG #qux ::)").unwrap();
+        assert_eq!(msg.params, vec!["#qux", ":)"]);
+    }
+
+    #[test]
+    fn borrows_from_the_line() {
+        let line = "@msgid=abc;+note=a\\sb :nick!user@host PRIVMSG #qux :hello there";
+        let msg = MessageRef::parse(line).unwrap();
+        assert_eq!(msg.command, Command::PRIVMSG);
+        assert_eq!(
+            msg.params().collect::<Vec<_>>(),
+            vec!["#qux", "hello there"]
+        );
+        let tags = msg.tags().collect::<Vec<_>>();
+        assert!(matches!(tags[0], ("msgid", Cow::Borrowed("abc"))));
+        assert_eq!(tags[1], ("+note", Cow::Owned::<str>("a b".to_string())));
+
+        assert_eq!(msg.tag("+note").as_deref(), Some("a b"));
+        assert_eq!(msg.tag("time"), None);
+        assert_eq!(msg.nick(), Some("nick"));
+
+        let owned = Message::from(msg);
+        assert_eq!(owned, parse_message(line).unwrap());
+        assert_eq!(owned.params, vec!["#qux", "hello there"]);
+
+        for source in ["irc.example.org", "nick", "nick@host", "a.b!c@d"] {
+            let line = format!(":{} PING x", source);
+            assert_eq!(
+                MessageRef::parse(&line).unwrap().nick(),
+                Source::parse(source).nick(),
+                "{}",
+                source
+            );
+        }
+        let msg = MessageRef::parse("@a=1;a=2 PING x").unwrap();
+        assert_eq!(msg.tag("a").as_deref(), Some("2"));
+        assert_eq!(msg.nick(), None);
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::collection::{btree_map, vec};
+        use proptest::option;
+        use proptest::prelude::*;
+
+        fn source() -> impl Strategy<Value = Source> {
+            prop_oneof![
+                "[a-z]{1,10}(\\.[a-z]{1,10}){1,3}".prop_map(Source::Server),
+                (
+                    "[a-zA-Z\\[\\]`^{}|_][a-zA-Z0-9\\[\\]`^{}|_-]{0,15}",
+                    option::of("~?[a-z]{1,10}"),
+                    option::of("[a-z0-9.:/-]{1,30}"),
+                )
+                    .prop_map(|(nick, user, host)| Source::User {
+                        nick,
+                        user,
+                        host
+                    }),
+            ]
+        }
+
+        fn command() -> impl Strategy<Value = Command> {
+            prop_oneof![
+                "[A-Z]{1,12}",
+                (0u16..1000).prop_map(|n| format!("{:03}", n)),
+            ]
+            .prop_map(|c| Command::from_str(&c).unwrap())
+        }
+
+        fn middle() -> impl Strategy<Value = String> {
+            "[^ :\\r\\n\\x00][^ \\r\\n\\x00]{0,20}"
+        }
+
+        fn message() -> impl Strategy<Value = Message> {
+            (
+                btree_map(
+                    "\\+?([a-z0-9.-]{1,10}/)?[a-zA-Z0-9-]{1,10}",
+                    "[^\\x00]{0,20}",
+                    0..4,
+                ),
+                option::of(source()),
+                command(),
+                vec(middle(), 0..5),
+                option::of("[^\\r\\n\\x00]{0,50}"),
+            )
+                .prop_map(|(tags, source, command, mut params, last)| {
+                    params.extend(last);
+                    Message {
+                        tags,
+                        source,
+                        command,
+                        params,
+                        decoded: Decoded::Utf8,
+                    }
+                })
+        }
+
+        proptest! {
+            #[test]
+            fn serialized_messages_parse_back(msg in message()) {
+                let line = msg.serialize().unwrap();
+                prop_assert_eq!(parse_message(&line).unwrap(), msg);
+            }
+
+            #[test]
+            fn never_panics(line in "\\PC*") {
+                let _ = parse_message(&line);
+            }
+
+            // lines shaped enough like IRC to get past the first few checks
+            #[test]
+            fn parsed_lines_round_trip(
+                line in "(@[a-z+/=;\\\\ -]{0,20} )?(:[a-z!@.:]{0,20} )? *[A-Za-z0-9]{0,8}( {1,2}:?[^\\r\\n]{0,30}){0,20}"
+            ) {
+                check_round_trip(&line);
+            }
+
+            #[test]
+            fn long_messages_fit(
+                tags in btree_map("\\+[a-z]{1,10}", "[^\\x00]{0,20}", 0..4),
+                source in option::of(source()),
+                command in command(),
+                mut params in vec(middle(), 0..5),
+                text in "[^\\r\\n\\x00]{400,1000}",
+            ) {
+                params.push(text);
+                let msg = Message { tags, source, command, params, decoded: Decoded::Utf8 };
+                let line = msg.serialize().unwrap();
+                // tags have a limit of their own
+                let start = if msg.tags.is_empty() { 0 } else { line.find(' ').unwrap() + 1 };
+                prop_assert!(line.len() - start <= MAX_LINE_LEN);
+                check_round_trip(&line);
+            }
+        }
+    }
+
+    // the fuzzing corpus doubles as a set of tricky real-world lines, and minimized inputs the
+    // fuzzers have failed on are kept in fuzz/regressions
+    #[test]
+    fn fuzz_corpus_round_trips() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz");
+        for dir in ["corpus/parse", "regressions"] {
+            for entry in fs::read_dir(root.join(dir)).unwrap() {
+                let data = fs::read(entry.unwrap().path()).unwrap();
+                let line = String::from_utf8_lossy(&data);
+                check_round_trip(line.split('\n').next().unwrap_or(""));
+            }
+        }
+    }
+
+    #[test]
+    fn parses_tricky_lines() {
+        let msg = parse_message("CMD a b c d e f g h i j k l m n o p q\r\n").unwrap();
+        assert_eq!(msg.command, Command::Other("CMD".to_string()));
+        assert_eq!(msg.params.len(), 17);
+
+        let msg = parse_message("@+typing=active TAGMSG #qux").unwrap();
+        assert_eq!(msg.command, Command::TAGMSG);
+        assert_eq!(msg.tags["+typing"], "active");
+
+        let msg = parse_message("@a=1;a=2 PING x").unwrap();
+        assert_eq!(msg.tags["a"], "2");
+
+        let line = String::from_utf8_lossy(b":nick!u@h PRIVMSG #qux :caf\xe9\r\n");
+        let msg = parse_message(&line).unwrap();
+        assert_eq!(msg.params[1], "caf\u{FFFD}");
+    }
+
+    #[test]
+    fn serializes_emptied_trailing_params() {
+        // found by fuzzing: truncating the last parameter down to nothing has to mark it as
+        // trailing, or it disappears
+        let line = format!(":{} 889 : x", "x".repeat(503));
+        let msg = parse_message(&line).unwrap();
+        let serialized = msg.serialize().unwrap();
+        assert!(serialized.ends_with(" 889 :\r\n"));
+        assert_eq!(parse_message(&serialized).unwrap().params, vec![""]);
+
+        // and if there's no room for the ':', it doesn't fit at all
+        let line = format!(":{} 889 i", "x".repeat(504));
+        let msg = parse_message(&line).unwrap();
+        assert!(matches!(msg.serialize(), Err(SerializeErr::TooLong(..))));
+    }
+
+    #[test]
+    fn parses_without_source() {
+        let msg = parse_message("PING :tantalum.libera.chat").unwrap();
+        assert!(msg.source.is_none());
+    }
 }
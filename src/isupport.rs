@@ -0,0 +1,294 @@
+use crate::casemap::CaseMapping;
+use crate::irc::Message;
+use std::collections::BTreeMap;
+
+/// The modes a channel can have, by whether they take a parameter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChanModes {
+    // type A: modes that add to or remove from a list, like bans, and always take a parameter
+    pub list: String,
+    // type B: modes that always take a parameter, like the channel key
+    pub always: String,
+    // type C: modes that only take a parameter when set, like the user limit
+    pub on_set: String,
+    // type D: modes that never take a parameter
+    pub never: String,
+}
+
+/// The features a server advertises in RPL_ISUPPORT (005), starting from the RFC 2812 defaults
+/// for a server that doesn't advertise them. See https://modern.ircdocs.horse/#rplisupport-005
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ISupport {
+    pub casemapping: CaseMapping,
+    // the characters a channel name can start with
+    pub chantypes: String,
+    // the modes that give a channel member a status, and the prefix shown for each, highest first
+    pub prefix: Vec<(char, char)>,
+    pub chanmodes: ChanModes,
+    pub nicklen: usize,
+    // the longest line the server accepts, including the CRLF but not tags
+    pub linelen: usize,
+    // None means there's no limit
+    pub topiclen: Option<usize>,
+    // the most modes with a parameter in one MODE command, where None means there's no limit
+    pub modes: Option<usize>,
+    // the most targets each command accepts, where None means there's no limit
+    pub targmax: BTreeMap<String, Option<usize>>,
+    pub network: Option<String>,
+    // the prefixes that can go in front of a channel to message only members with that status
+    pub statusmsg: String,
+    // the modes for ban exceptions and invite exceptions, if the server has them
+    pub excepts: Option<char>,
+    pub invex: Option<char>,
+    // how many nicks MONITOR can watch, if it's supported at all
+    pub monitor: Option<usize>,
+    pub whox: bool,
+    pub utf8only: bool,
+    // every token as it was sent, with values still escaped, for anything not covered above
+    pub raw: BTreeMap<String, String>,
+}
+
+impl Default for ISupport {
+    fn default() -> ISupport {
+        ISupport {
+            casemapping: CaseMapping::Rfc1459,
+            chantypes: "#&".to_string(),
+            prefix: vec![('o', '@'), ('v', '+')],
+            chanmodes: ChanModes {
+                list: "b".to_string(),
+                always: "k".to_string(),
+                on_set: "l".to_string(),
+                never: "imnpst".to_string(),
+            },
+            nicklen: 9,
+            linelen: 512,
+            topiclen: None,
+            modes: Some(3),
+            targmax: BTreeMap::new(),
+            network: None,
+            statusmsg: String::new(),
+            excepts: None,
+            invex: None,
+            monitor: None,
+            whox: false,
+            utf8only: false,
+            raw: BTreeMap::new(),
+        }
+    }
+}
+
+impl ISupport {
+    /// Applies the tokens of an RPL_ISUPPORT line. The first parameter is our nick and the last
+    /// is a human readable "are supported by this server", so only the ones between are tokens.
+    pub fn update(&mut self, msg: &Message) {
+        let Some(tokens) = msg.params.get(1..msg.params.len().saturating_sub(1)) else {
+            return;
+        };
+        for token in tokens {
+            // a leading '-' means a token that was advertised before no longer applies
+            if let Some(name) = token.strip_prefix('-') {
+                self.raw.remove(name);
+                self.set(name, None);
+                continue;
+            }
+            let (name, value) = token.split_once('=').unwrap_or((token, ""));
+            self.raw.insert(name.to_string(), value.to_string());
+            self.set(name, Some(&unescape(value)));
+        }
+    }
+
+    // sets a token to the given value, or back to its default when it's None
+    fn set(&mut self, name: &str, value: Option<&str>) {
+        let default = ISupport::default();
+        let number = |v: Option<&str>| v.and_then(|v| v.parse::<usize>().ok());
+        match (name, value) {
+            ("CASEMAPPING", Some(v)) => self.casemapping = CaseMapping::from_token(v),
+            ("CASEMAPPING", None) => self.casemapping = default.casemapping,
+            ("CHANTYPES", v) => self.chantypes = v.map_or(default.chantypes, str::to_string),
+            ("PREFIX", Some(v)) => self.prefix = parse_prefix(v).unwrap_or(default.prefix),
+            ("PREFIX", None) => self.prefix = default.prefix,
+            ("CHANMODES", Some(v)) => {
+                let mut types = v.split(',').map(str::to_string);
+                self.chanmodes = ChanModes {
+                    list: types.next().unwrap_or_default(),
+                    always: types.next().unwrap_or_default(),
+                    on_set: types.next().unwrap_or_default(),
+                    never: types.next().unwrap_or_default(),
+                };
+            }
+            ("CHANMODES", None) => self.chanmodes = default.chanmodes,
+            ("NICKLEN", v) => self.nicklen = number(v).unwrap_or(default.nicklen),
+            ("LINELEN", v) => self.linelen = number(v).unwrap_or(default.linelen),
+            ("TOPICLEN", v) => self.topiclen = number(v),
+            ("MODES", Some(v)) => self.modes = number(Some(v)),
+            ("MODES", None) => self.modes = default.modes,
+            ("TARGMAX", v) => {
+                self.targmax = v
+                    .unwrap_or("")
+                    .split(',')
+                    .filter_map(|t| t.split_once(':'))
+                    .map(|(cmd, max)| (cmd.to_ascii_uppercase(), number(Some(max))))
+                    .collect();
+            }
+            ("NETWORK", v) => self.network = v.map(str::to_string),
+            ("STATUSMSG", v) => self.statusmsg = v.unwrap_or("").to_string(),
+            ("EXCEPTS", v) => self.excepts = v.map(|v| v.chars().next().unwrap_or('e')),
+            ("INVEX", v) => self.invex = v.map(|v| v.chars().next().unwrap_or('I')),
+            ("MONITOR", v) => self.monitor = v.map(|v| number(Some(v)).unwrap_or(usize::MAX)),
+            ("WHOX", v) => self.whox = v.is_some(),
+            ("UTF8ONLY", v) => self.utf8only = v.is_some(),
+            _ => {}
+        }
+    }
+
+    /// The most targets the server accepts in one of the command, where None means there's
+    /// no limit. A command TARGMAX doesn't list is taken to have none either.
+    pub fn max_targets(&self, command: &str) -> Option<usize> {
+        self.targmax.get(command).copied().flatten()
+    }
+
+    /// Returns true if the target is a channel rather than a nick, including a channel with a
+    /// STATUSMSG prefix like `@#chan`
+    pub fn is_channel(&self, target: &str) -> bool {
+        let target = target.trim_start_matches(|c| self.statusmsg.contains(c));
+        target.starts_with(|c| self.chantypes.contains(c))
+    }
+}
+
+// PREFIX is in the form of "(modes)prefixes", with the two lists paired up in order
+fn parse_prefix(v: &str) -> Option<Vec<(char, char)>> {
+    if v.is_empty() {
+        return Some(vec![]);
+    }
+    let (modes, prefixes) = v.strip_prefix('(')?.split_once(')')?;
+    if modes.chars().count() != prefixes.chars().count() {
+        return None;
+    }
+    Some(modes.chars().zip(prefixes.chars()).collect())
+}
+
+// values escape any character as \xHH, usually for spaces and '='
+fn unescape(v: &str) -> String {
+    let mut out = Vec::with_capacity(v.len());
+    let bytes = v.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i..i + 4)
+            .filter(|b| b.starts_with(b"\\x"))
+            .and_then(|b| std::str::from_utf8(&b[2..]).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(b) => {
+                out.push(b);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::parse_message;
+
+    fn isupport(lines: &[&str]) -> ISupport {
+        let mut isupport = ISupport::default();
+        for line in lines {
+            isupport.update(&parse_message(line).unwrap());
+        }
+        isupport
+    }
+
+    #[test]
+    fn parses_libera() {
+        let s = isupport(&[
+            ":tantalum.libera.chat 005 ircrab CALLERID=g WHOX ETRACE FNC SAFELIST ELIST=CMNTU KNOCK MONITOR=100 CHANTYPES=# EXCEPTS INVEX CHANMODES=eIbq,k,flj,CFLMPQScgimnprstuz :are supported by this server",
+            ":tantalum.libera.chat 005 ircrab CHANLIMIT=#:250 PREFIX=(ov)@+ MAXLIST=bqeI:100 MODES=4 NETWORK=Libera.Chat STATUSMSG=@+ CASEMAPPING=rfc1459 NICKLEN=16 MAXNICKLEN=16 CHANNELLEN=50 TOPICLEN=390 DEAF=D :are supported by this server",
+            ":tantalum.libera.chat 005 ircrab TARGMAX=NAMES:1,LIST:1,KICK:1,WHOIS:1,PRIVMSG:4,NOTICE:4,ACCEPT:,MONITOR: EXTBAN=$,agjrxz :are supported by this server",
+        ]);
+        assert_eq!(s.casemapping, CaseMapping::Rfc1459);
+        assert_eq!(s.chantypes, "#");
+        assert_eq!(s.prefix, vec![('o', '@'), ('v', '+')]);
+        assert_eq!(s.chanmodes.list, "eIbq");
+        assert_eq!(s.chanmodes.always, "k");
+        assert_eq!(s.chanmodes.on_set, "flj");
+        assert_eq!(s.chanmodes.never, "CFLMPQScgimnprstuz");
+        assert_eq!(s.nicklen, 16);
+        assert_eq!(s.topiclen, Some(390));
+        assert_eq!(s.modes, Some(4));
+        assert_eq!(s.network.as_deref(), Some("Libera.Chat"));
+        assert_eq!(s.statusmsg, "@+");
+        assert_eq!(s.excepts, Some('e'));
+        assert_eq!(s.invex, Some('I'));
+        assert_eq!(s.monitor, Some(100));
+        assert!(s.whox);
+        assert!(!s.utf8only);
+        assert_eq!(s.targmax["PRIVMSG"], Some(4));
+        assert_eq!(s.targmax["ACCEPT"], None);
+        assert!(!s.targmax.contains_key("JOIN"));
+        assert_eq!(s.max_targets("NOTICE"), Some(4));
+        assert_eq!(s.max_targets("MONITOR"), None);
+        assert_eq!(s.max_targets("JOIN"), None);
+        assert_eq!(s.raw["DEAF"], "D");
+    }
+
+    #[test]
+    fn defaults_to_the_rfc() {
+        let s = ISupport::default();
+        assert_eq!(s.casemapping, CaseMapping::Rfc1459);
+        assert!(s.is_channel("#qux"));
+        assert!(s.is_channel("&qux"));
+        assert!(!s.is_channel("+qux"));
+        assert!(!s.is_channel("ircrab"));
+        assert_eq!(s.nicklen, 9);
+        assert_eq!(s.linelen, 512);
+        assert_eq!(s.modes, Some(3));
+        assert!(s.targmax.is_empty());
+    }
+
+    #[test]
+    fn handles_odd_values() {
+        let s = isupport(&[
+            "005 ircrab CASEMAPPING=ascii PREFIX=(qaohv)~&@%+ NETWORK=Some\\x20Net\\x3D MODES MONITOR EXCEPTS=E UTF8ONLY LINELEN=2048 :are supported",
+        ]);
+        assert_eq!(s.casemapping, CaseMapping::Ascii);
+        assert_eq!(s.prefix[0], ('q', '~'));
+        assert_eq!(s.prefix.len(), 5);
+        assert_eq!(s.network.as_deref(), Some("Some Net="));
+        assert_eq!(s.modes, None);
+        assert_eq!(s.monitor, Some(usize::MAX));
+        assert_eq!(s.excepts, Some('E'));
+        assert!(s.utf8only);
+        assert_eq!(s.linelen, 2048);
+
+        // a malformed PREFIX keeps the default rather than half of what was sent
+        let s = isupport(&["005 ircrab PREFIX=(ov)@ :are supported"]);
+        assert_eq!(s.prefix, vec![('o', '@'), ('v', '+')]);
+        let s = isupport(&["005 ircrab PREFIX= :are supported"]);
+        assert!(s.prefix.is_empty());
+    }
+
+    #[test]
+    fn negates_tokens() {
+        let s = isupport(&[
+            "005 ircrab CHANTYPES=# WHOX NICKLEN=30 :are supported",
+            "005 ircrab -CHANTYPES -WHOX -NICKLEN :are supported",
+        ]);
+        assert_eq!(s, ISupport::default());
+    }
+
+    #[test]
+    fn checks_status_targets() {
+        let s = isupport(&["005 ircrab CHANTYPES=# STATUSMSG=@+ :are supported"]);
+        assert!(s.is_channel("@#qux"));
+        assert!(s.is_channel("+#qux"));
+        assert!(!s.is_channel("&qux"));
+        assert!(!s.is_channel("@nick"));
+    }
+}
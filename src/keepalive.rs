@@ -0,0 +1,150 @@
+use crate::irc::{Command, Message};
+use std::time::{Duration, Instant};
+
+// the payload of our own PINGs, which the server echoes back in its PONG
+const TOKEN: &str = "ircrab-keepalive";
+
+/// When to check on a quiet connection, and how long to wait before giving up on it
+#[derive(Clone, PartialEq, Eq)]
+pub struct Keepalive {
+    // send a PING after this long without hearing from the server
+    pub idle: Duration,
+    // declare the connection dead if nothing arrives this long after our PING
+    pub timeout: Duration,
+}
+
+/// Tracks traffic on a single connection to detect when it has silently died
+pub struct Monitor {
+    cfg: Keepalive,
+    last_activity: Instant,
+    ping_sent: Option<Instant>,
+}
+
+impl Monitor {
+    pub fn new(cfg: &Keepalive, now: Instant) -> Monitor {
+        Monitor {
+            cfg: cfg.clone(),
+            last_activity: now,
+            ping_sent: None,
+        }
+    }
+
+    /// Record that something arrived from the server. Any traffic proves the connection is alive,
+    /// not just a PONG.
+    pub fn activity(&mut self, now: Instant) {
+        self.last_activity = now;
+        self.ping_sent = None;
+    }
+
+    /// How long a read may block before `poll` needs to be called
+    pub fn read_timeout(&self, now: Instant) -> Duration {
+        let deadline = match self.ping_sent {
+            Some(sent) => sent + self.cfg.timeout,
+            None => self.last_activity + self.cfg.idle,
+        };
+        // a zero timeout means no timeout at all to the socket
+        deadline
+            .saturating_duration_since(now)
+            .max(Duration::from_millis(1))
+    }
+
+    /// Check on the connection, returning a PING to send if it has gone quiet,
+    /// or an error if it's dead
+    pub fn poll(&mut self, now: Instant) -> Result<Option<Message>, String> {
+        match self.ping_sent {
+            Some(sent) if now.duration_since(sent) >= self.cfg.timeout => Err(format!(
+                "ping timeout: no response in {:?}",
+                self.cfg.timeout
+            )),
+            Some(_) => Ok(None),
+            None if now.duration_since(self.last_activity) >= self.cfg.idle => {
+                self.ping_sent = Some(now);
+                Ok(Some(Message::new(Command::PING, vec![TOKEN.to_string()])))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(start: Instant) -> Monitor {
+        Monitor::new(
+            &Keepalive {
+                idle: Duration::from_secs(60),
+                timeout: Duration::from_secs(30),
+            },
+            start,
+        )
+    }
+
+    #[test]
+    fn pings_when_idle() {
+        let start = Instant::now();
+        let mut m = monitor(start);
+        assert_eq!(m.read_timeout(start), Duration::from_secs(60));
+        assert!(m.poll(start + Duration::from_secs(59)).unwrap().is_none());
+
+        let ping = m.poll(start + Duration::from_secs(60)).unwrap().unwrap();
+        assert!(ping.command == Command::PING);
+        assert_eq!(ping.params, vec![TOKEN]);
+        assert_eq!(
+            m.read_timeout(start + Duration::from_secs(60)),
+            Duration::from_secs(30)
+        );
+        // only one PING is outstanding at a time
+        assert!(m.poll(start + Duration::from_secs(61)).unwrap().is_none());
+    }
+
+    #[test]
+    fn times_out_without_response() {
+        let start = Instant::now();
+        let mut m = monitor(start);
+        m.poll(start + Duration::from_secs(60)).unwrap();
+        assert!(m.poll(start + Duration::from_secs(89)).is_ok());
+        assert_eq!(
+            m.poll(start + Duration::from_secs(90)).unwrap_err(),
+            "ping timeout: no response in 30s"
+        );
+
+        // a timeout under a second isn't rounded down to nothing
+        let mut m = Monitor::new(
+            &Keepalive {
+                idle: Duration::from_secs(1),
+                timeout: Duration::from_millis(500),
+            },
+            start,
+        );
+        m.poll(start + Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            m.poll(start + Duration::from_millis(1500)).unwrap_err(),
+            "ping timeout: no response in 500ms"
+        );
+    }
+
+    #[test]
+    fn any_traffic_resets() {
+        let start = Instant::now();
+        let mut m = monitor(start);
+        m.poll(start + Duration::from_secs(60)).unwrap();
+        m.activity(start + Duration::from_secs(70));
+        assert!(m.poll(start + Duration::from_secs(100)).unwrap().is_none());
+        assert_eq!(
+            m.read_timeout(start + Duration::from_secs(100)),
+            Duration::from_secs(30)
+        );
+        assert!(m.poll(start + Duration::from_secs(130)).unwrap().is_some());
+    }
+
+    #[test]
+    fn never_returns_a_zero_timeout() {
+        let start = Instant::now();
+        let m = monitor(start);
+        assert_eq!(
+            m.read_timeout(start + Duration::from_secs(600)),
+            Duration::from_millis(1)
+        );
+    }
+}
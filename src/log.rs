@@ -0,0 +1,147 @@
+use crate::secret;
+use once_cell::sync::Lazy;
+use std::fmt;
+use std::io::Write;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How much is worth logging. Each level includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    // every line sent and received
+    Debug,
+    // including keepalive traffic
+    Trace,
+}
+
+impl Level {
+    /// The level for a verbosity, where each -v counts 1 and each -q counts -1 from the
+    /// default of Info
+    pub fn from_verbosity(verbosity: i32) -> Level {
+        match verbosity {
+            ..=-2 => Level::Error,
+            -1 => Level::Warn,
+            0 => Level::Info,
+            1 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+/// How log lines are written
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    // for people
+    Text,
+    // one object per line, for log collectors
+    Json,
+}
+
+struct Logger {
+    level: Level,
+    format: Format,
+}
+
+static LOGGER: Lazy<RwLock<Logger>> = Lazy::new(|| {
+    RwLock::new(Logger {
+        level: Level::Info,
+        format: Format::Text,
+    })
+});
+
+pub fn init(level: Level, format: Format) {
+    *LOGGER.write().unwrap() = Logger { level, format };
+}
+
+/// Writes a line to stderr if `level` is being logged, with any secrets scrubbed out.
+/// Use the macros instead.
+pub fn write(level: Level, args: fmt::Arguments) {
+    let logger = LOGGER.read().unwrap();
+    if level > logger.level {
+        return;
+    }
+    let message = args.to_string();
+    let line = format(
+        logger.format,
+        level,
+        &secret::scrub(&message),
+        SystemTime::now(),
+    );
+    // there's nowhere left to report a failure to log
+    writeln!(std::io::stderr().lock(), "{}", line).ok();
+}
+
+fn format(format: Format, level: Level, message: &str, at: SystemTime) -> String {
+    match format {
+        Format::Text => format!("{:<5} {}", level.name().to_uppercase(), message),
+        Format::Json => serde_json::json!({
+            "time": at.duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64()),
+            "level": level.name(),
+            "message": message,
+        })
+        .to_string(),
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Error, format_args!($($arg)*)) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Warn, format_args!($($arg)*)) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Info, format_args!($($arg)*)) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Debug, format_args!($($arg)*)) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Trace, format_args!($($arg)*)) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_lines() {
+        let at = UNIX_EPOCH + Duration::from_millis(1_500);
+        assert_eq!(
+            format(Format::Text, Level::Warn, "queue is full", at),
+            "WARN  queue is full"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&format(Format::Json, Level::Debug, "say \"hi\"\n", at)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"time": 1.5, "level": "debug", "message": "say \"hi\"\n"})
+        );
+    }
+
+    #[test]
+    fn counts_verbosity() {
+        assert_eq!(Level::from_verbosity(0), Level::Info);
+        assert_eq!(Level::from_verbosity(-1), Level::Warn);
+        assert_eq!(Level::from_verbosity(-5), Level::Error);
+        assert_eq!(Level::from_verbosity(2), Level::Trace);
+        assert!(Level::Trace > Level::Debug);
+    }
+}
@@ -1,33 +1,159 @@
+use clap::Parser;
+use cli::Command;
+use std::io::{self, BufRead};
+use std::process;
+
+#[macro_use]
+mod log;
+
+mod backoff;
 mod bot;
+mod cap;
+mod casemap;
+mod cli;
+mod config;
+mod delivery;
+mod encoding;
 mod irc;
+mod isupport;
+mod keepalive;
+mod mode;
+mod nick;
+mod nickserv;
+mod queue;
+mod reload;
+mod sasl;
+mod secret;
+mod split;
+mod state;
+mod throttle;
+mod tls;
 mod triggers;
 
 pub struct Config {
     my_nick: String,
+    // tried in order if my_nick is taken, before falling back to my_nick_, my_nick1 and so on
+    alt_nicks: Vec<String>,
+    username: String,
+    realname: String,
     network: Network,
+    // IRCv3 capabilities to request, if the server offers them
+    capabilities: Vec<String>,
+    // if set, registration is aborted unless SASL authentication succeeds
+    sasl: Option<sasl::Mechanism>,
+    // identifies with NickServ once registered, if SASL isn't set
+    nickserv: Option<nickserv::NickServ>,
+    // the server password, sent with PASS
+    pass: Option<secret::Secret>,
+    // how long to wait between attempts to reconnect
+    reconnect: backoff::Backoff,
+    // how to detect a connection that has silently died
+    keepalive: keepalive::Keepalive,
+    // how fast messages may be sent
+    flood: throttle::FloodControl,
+    // how many low priority messages may wait to be sent before the oldest are dropped
+    bulk_queue_limit: usize,
+    // how replies that are too long for one line are broken up
+    splitting: split::Splitting,
+    triggers: triggers::Settings,
 }
+
 pub struct Network {
     host: String,
     port: u16,
     ssl: bool,
-    channel: String,
+    // only used if ssl is true
+    tls: tls::Options,
+    // joined once registered, if the on_connect trigger is enabled
+    channels: Vec<Channel>,
+    encoding: encoding::Encoding,
 }
 
-fn initialize() -> Config {
-    Config {
-        my_nick: "ircrab".to_string(),
-        network: Network {
-            host: "irc.libera.chat".to_string(),
-            port: 6667,
-            ssl: false,
-            channel: "##cwru-testing".to_string(),
-        },
-    }
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Channel {
+    name: String,
+    key: Option<String>,
 }
 
 fn main() {
-    println!("Initializing...");
-    let cfg = initialize();
-    let b = bot::new(cfg);
-    b.run().unwrap();
+    let cli = cli::Cli::parse();
+    log::init(cli.log_level(), cli.log_format);
+    match cli.subcommand() {
+        Command::Run => run(&cli),
+        Command::CheckConfig => {
+            let cfg = load(&cli);
+            println!(
+                "{} is valid, and connects to {}:{}",
+                cli.config.display(),
+                cfg.network.host,
+                cfg.network.port
+            );
+        }
+        Command::PrintDefaultConfig => print!("{}", config::SAMPLE),
+        Command::Send { target, text } => {
+            if let Err(e) = bot::new(load(&cli)).deliver(&target, &text) {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
+        Command::Parse => parse(),
+    }
+}
+
+// loads the config, or exits explaining what's wrong with it
+fn load(cli: &cli::Cli) -> Config {
+    config::load(&cli.config, cli.network.as_deref()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        eprintln!("a config to start from is printed by `ircrab print-default-config`");
+        process::exit(1);
+    })
+}
+
+fn run(cli: &cli::Cli) {
+    info!("Initializing from {}...", cli.config.display());
+    let cfg = load(cli);
+    let source = reload::Source::new(cli.config.clone(), cli.network.clone());
+    #[cfg(unix)]
+    if let Err(e) =
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, source.requested.clone())
+    {
+        warn!("Can't reload the config on SIGHUP: {}", e);
+    }
+    let b = bot::new(cfg).reloads_from(source);
+    let status = b.status();
+    if let Err(e) = b.run() {
+        error!("{}", e);
+        error!("Connection status at exit: {}", status.read().unwrap());
+        process::exit(1);
+    }
+}
+
+// prints each line on stdin as JSON, exiting unsuccessfully if any didn't parse
+fn parse() {
+    let mut failed = false;
+    for bytes in io::stdin().lock().split(b'\n') {
+        let bytes = bytes.unwrap_or_else(|e| {
+            error!("can't read stdin: {}", e);
+            process::exit(1);
+        });
+        let (line, decoded) = encoding::decode(&bytes, encoding::Charset::Cp1252);
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            continue;
+        }
+        let json = match irc::parse_message(line) {
+            Ok(mut msg) => {
+                msg.decoded = decoded;
+                cli::to_json(&msg)
+            }
+            Err(e) => {
+                failed = true;
+                serde_json::json!({ "error": e.to_string(), "line": line })
+            }
+        };
+        println!("{}", json);
+    }
+    if failed {
+        process::exit(1);
+    }
 }
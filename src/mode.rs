@@ -0,0 +1,420 @@
+use crate::irc::{Command, Message};
+use crate::isupport::ISupport;
+use crate::split;
+
+/// A single mode set or unset by a MODE command
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ModeChange {
+    // a status from PREFIX given to or taken from a member, like +o nick
+    Status {
+        set: bool,
+        mode: char,
+        nick: String,
+    },
+    // CHANMODES type A: an entry added to or removed from a list, like +b mask
+    List {
+        set: bool,
+        mode: char,
+        entry: String,
+    },
+    // CHANMODES types B and C: a setting with a parameter, like +k key or +l 10.
+    // type C only has one when it's set.
+    Param {
+        set: bool,
+        mode: char,
+        param: Option<String>,
+    },
+    // CHANMODES type D, and any mode the server didn't tell us about: a flag, like +m
+    Flag {
+        set: bool,
+        mode: char,
+    },
+}
+
+impl ModeChange {
+    /// Returns true if the mode is being set rather than unset
+    pub fn is_set(&self) -> bool {
+        match self {
+            ModeChange::Status { set, .. }
+            | ModeChange::List { set, .. }
+            | ModeChange::Param { set, .. }
+            | ModeChange::Flag { set, .. } => *set,
+        }
+    }
+
+    pub fn mode(&self) -> char {
+        match self {
+            ModeChange::Status { mode, .. }
+            | ModeChange::List { mode, .. }
+            | ModeChange::Param { mode, .. }
+            | ModeChange::Flag { mode, .. } => *mode,
+        }
+    }
+
+    pub fn param(&self) -> Option<&str> {
+        match self {
+            ModeChange::Status { nick, .. } => Some(nick),
+            ModeChange::List { entry, .. } => Some(entry),
+            ModeChange::Param { param, .. } => param.as_deref(),
+            ModeChange::Flag { .. } => None,
+        }
+    }
+}
+
+/// Walks a mode string like `+o-v+b`, taking each mode's parameter from `params` in turn.
+/// Which modes take a parameter comes from PREFIX and CHANMODES. A mode that's missing its
+/// parameter ends the walk, since nothing after it can be matched up reliably.
+pub fn parse(modes: &str, params: &[String], support: &ISupport) -> Vec<ModeChange> {
+    let mut params = params.iter().cloned();
+    let mut changes = vec![];
+    let mut set = true;
+    for mode in modes.chars() {
+        let chanmodes = &support.chanmodes;
+        let change = match mode {
+            '+' | '-' => {
+                set = mode == '+';
+                continue;
+            }
+            _ if support.prefix.iter().any(|&(m, _)| m == mode) => params
+                .next()
+                .map(|nick| ModeChange::Status { set, mode, nick }),
+            _ if chanmodes.list.contains(mode) => {
+                params
+                    .next()
+                    .map(|entry| ModeChange::List { set, mode, entry })
+            }
+            _ if chanmodes.always.contains(mode) || (set && chanmodes.on_set.contains(mode)) => {
+                params.next().map(|param| ModeChange::Param {
+                    set,
+                    mode,
+                    param: Some(param),
+                })
+            }
+            _ if chanmodes.on_set.contains(mode) => Some(ModeChange::Param {
+                set,
+                mode,
+                param: None,
+            }),
+            _ => Some(ModeChange::Flag { set, mode }),
+        };
+        match change {
+            Some(change) => changes.push(change),
+            None => break,
+        }
+    }
+    changes
+}
+
+/// The mode changes in a MODE message, if it's for a channel
+pub fn changes(msg: &Message, support: &ISupport) -> Vec<ModeChange> {
+    match msg.params.as_slice() {
+        [target, modes, params @ ..]
+            if msg.command == Command::MODE && support.is_channel(target) =>
+        {
+            parse(modes, params, support)
+        }
+        _ => vec![],
+    }
+}
+
+/// Collects mode changes for a target and sends them in as few MODE lines as the server allows
+pub struct Builder {
+    target: String,
+    changes: Vec<ModeChange>,
+}
+
+impl Builder {
+    pub fn new(target: &str) -> Builder {
+        Builder {
+            target: target.to_string(),
+            changes: vec![],
+        }
+    }
+
+    pub fn change(mut self, change: ModeChange) -> Builder {
+        self.changes.push(change);
+        self
+    }
+
+    pub fn op(self, nick: &str) -> Builder {
+        self.status(true, 'o', nick)
+    }
+
+    pub fn deop(self, nick: &str) -> Builder {
+        self.status(false, 'o', nick)
+    }
+
+    pub fn voice(self, nick: &str) -> Builder {
+        self.status(true, 'v', nick)
+    }
+
+    pub fn devoice(self, nick: &str) -> Builder {
+        self.status(false, 'v', nick)
+    }
+
+    pub fn ban(self, mask: &str) -> Builder {
+        self.change(ModeChange::List {
+            set: true,
+            mode: 'b',
+            entry: mask.to_string(),
+        })
+    }
+
+    pub fn unban(self, mask: &str) -> Builder {
+        self.change(ModeChange::List {
+            set: false,
+            mode: 'b',
+            entry: mask.to_string(),
+        })
+    }
+
+    fn status(self, set: bool, mode: char, nick: &str) -> Builder {
+        self.change(ModeChange::Status {
+            set,
+            mode,
+            nick: nick.to_string(),
+        })
+    }
+
+    /// The MODE lines for every change, in order. Each has at most as many changes with a
+    /// parameter as the server's MODES allows, and fits in a line once relayed.
+    pub fn build(&self, support: &ISupport) -> Vec<Message> {
+        let max_params = support.modes.unwrap_or(usize::MAX).max(1);
+        let budget = split::budget(None, &Command::MODE, &self.target, support.linelen);
+        let mut lines = vec![];
+        let mut line = Line::default();
+        for change in &self.changes {
+            let full = change.param().is_some() && line.params.len() >= max_params;
+            if !line.modes.is_empty() && (full || line.len_with(change) > budget) {
+                lines.push(std::mem::take(&mut line).into_message(&self.target));
+            }
+            line.push(change);
+        }
+        if !line.modes.is_empty() {
+            lines.push(line.into_message(&self.target));
+        }
+        lines
+    }
+}
+
+// one outgoing MODE line being put together
+#[derive(Default)]
+struct Line {
+    modes: String,
+    params: Vec<String>,
+    // whether the last sign written was '+', if any has been
+    set: Option<bool>,
+}
+
+impl Line {
+    fn push(&mut self, change: &ModeChange) {
+        if self.set != Some(change.is_set()) {
+            self.set = Some(change.is_set());
+            self.modes.push(if change.is_set() { '+' } else { '-' });
+        }
+        self.modes.push(change.mode());
+        if let Some(param) = change.param() {
+            self.params.push(param.to_string());
+        }
+    }
+
+    // the length of the modes and their parameters with another change added
+    fn len_with(&self, change: &ModeChange) -> usize {
+        let sign = usize::from(self.set != Some(change.is_set()));
+        let param = change.param().map_or(0, |p| p.len() + 1);
+        self.modes.len() + sign + 1 + self.params.iter().map(|p| p.len() + 1).sum::<usize>() + param
+    }
+
+    fn into_message(self, target: &str) -> Message {
+        let mut params = vec![target.to_string(), self.modes];
+        params.extend(self.params);
+        Message::new(Command::MODE, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::parse_message;
+
+    fn support(line: &str) -> ISupport {
+        let mut support = ISupport::default();
+        support.update(&parse_message(line).unwrap());
+        support
+    }
+
+    fn params(params: &str) -> Vec<String> {
+        params.split(' ').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn parses_by_chanmodes() {
+        let s = support("005 ircrab PREFIX=(qohv)~@%+ CHANMODES=beI,k,fl,imnpst :are supported");
+        let changes = parse(
+            "+ob-v+kl-lk+m-e+x",
+            &params("alice *!*@spam bob secret 10 secret *!*@ok"),
+            &s,
+        );
+        assert_eq!(
+            changes,
+            vec![
+                ModeChange::Status {
+                    set: true,
+                    mode: 'o',
+                    nick: "alice".to_string()
+                },
+                ModeChange::List {
+                    set: true,
+                    mode: 'b',
+                    entry: "*!*@spam".to_string()
+                },
+                ModeChange::Status {
+                    set: false,
+                    mode: 'v',
+                    nick: "bob".to_string()
+                },
+                ModeChange::Param {
+                    set: true,
+                    mode: 'k',
+                    param: Some("secret".to_string())
+                },
+                ModeChange::Param {
+                    set: true,
+                    mode: 'l',
+                    param: Some("10".to_string())
+                },
+                // unsetting a type C mode takes no parameter, but type B still does
+                ModeChange::Param {
+                    set: false,
+                    mode: 'l',
+                    param: None
+                },
+                ModeChange::Param {
+                    set: false,
+                    mode: 'k',
+                    param: Some("secret".to_string())
+                },
+                ModeChange::Flag {
+                    set: true,
+                    mode: 'm'
+                },
+                ModeChange::List {
+                    set: false,
+                    mode: 'e',
+                    entry: "*!*@ok".to_string()
+                },
+                // an unknown mode is taken to be a flag
+                ModeChange::Flag {
+                    set: true,
+                    mode: 'x'
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_missing_params() {
+        let s = ISupport::default();
+        assert_eq!(
+            parse("+vo-m", &params("alice"), &s),
+            vec![ModeChange::Status {
+                set: true,
+                mode: 'v',
+                nick: "alice".to_string()
+            }]
+        );
+        // a list query, as clients send it
+        assert_eq!(parse("+b", &[], &s), vec![]);
+        assert_eq!(parse("", &[], &s), vec![]);
+    }
+
+    #[test]
+    fn ignores_user_modes() {
+        let s = ISupport::default();
+        let msg = parse_message(":ircrab MODE ircrab :+iw").unwrap();
+        assert_eq!(changes(&msg, &s), vec![]);
+        let msg = parse_message(":srv MODE #cwru +o ircrab").unwrap();
+        assert_eq!(changes(&msg, &s).len(), 1);
+    }
+
+    #[test]
+    fn batches_by_modes_limit() {
+        let s = support("005 ircrab MODES=3 :are supported");
+        let lines: Vec<_> = Builder::new("#cwru")
+            .op("a")
+            .op("b")
+            .deop("c")
+            .voice("d")
+            .change(ModeChange::Flag {
+                set: true,
+                mode: 'm',
+            })
+            .ban("*!*@spam")
+            .build(&s)
+            .iter()
+            .map(|m| m.serialize().unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "MODE #cwru +oo-o a b c\r\n",
+                "MODE #cwru +vmb d *!*@spam\r\n"
+            ]
+        );
+
+        // each line still has to fit
+        let s = support("005 ircrab MODES :are supported");
+        let mask = "x".repeat(100);
+        let lines = (0..10)
+            .fold(Builder::new("#cwru"), |b, _| b.ban(&mask))
+            .build(&s);
+        assert_eq!(lines.len(), 4);
+        assert!(lines.iter().all(|m| m.serialize().unwrap().len() < 400));
+        assert_eq!(Builder::new("#cwru").build(&s), vec![]);
+    }
+
+    #[test]
+    fn round_trips() {
+        let s = support("005 ircrab CHANMODES=beI,k,l,imnpst :are supported");
+        let sent = Builder::new("#cwru")
+            .voice("a")
+            .unban("*!*@x")
+            .change(ModeChange::Param {
+                set: true,
+                mode: 'l',
+                param: Some("5".to_string()),
+            })
+            .change(ModeChange::Param {
+                set: false,
+                mode: 'l',
+                param: None,
+            })
+            .build(&s);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            changes(&sent[0], &s),
+            vec![
+                ModeChange::Status {
+                    set: true,
+                    mode: 'v',
+                    nick: "a".to_string()
+                },
+                ModeChange::List {
+                    set: false,
+                    mode: 'b',
+                    entry: "*!*@x".to_string()
+                },
+                ModeChange::Param {
+                    set: true,
+                    mode: 'l',
+                    param: Some("5".to_string())
+                },
+                ModeChange::Param {
+                    set: false,
+                    mode: 'l',
+                    param: None
+                },
+            ]
+        );
+    }
+}
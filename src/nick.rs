@@ -0,0 +1,268 @@
+use crate::irc::{Command, Message};
+use crate::state::State;
+use std::time::{Duration, Instant};
+
+// how often to ask whether our nick is free, when the server can't tell us with MONITOR
+const ISON_INTERVAL: Duration = Duration::from_secs(60);
+
+// how many numbered nicks to try once the alternates are all taken
+const NUMBERED: usize = 9;
+
+// how we're watching for our nick to come free, once we've had to settle for another
+#[derive(Debug, PartialEq, Eq)]
+enum Regain {
+    // we have it, or we've given up on it
+    Idle,
+    // the server tells us when it goes offline
+    Monitor,
+    // we ask the server when this comes around
+    Ison(Instant),
+}
+
+/// Gets us a nick on a single connection: tries alternates while registering when ours is taken,
+/// and takes ours back once it's free.
+pub struct Recovery {
+    primary: String,
+    // the nicks to try in turn if the primary is taken: the alternates, then generated ones
+    fallbacks: Vec<String>,
+    tried: usize,
+    registered: bool,
+    regain: Regain,
+}
+
+impl Recovery {
+    pub fn new(primary: &str, alternates: &[String]) -> Recovery {
+        let mut fallbacks = alternates.to_vec();
+        fallbacks.push(format!("{}_", primary));
+        fallbacks.extend((1..=NUMBERED).map(|i| format!("{}{}", primary, i)));
+        Recovery {
+            primary: primary.to_string(),
+            fallbacks,
+            tried: 0,
+            registered: false,
+            regain: Regain::Idle,
+        }
+    }
+
+    /// The NICK to register with
+    pub fn start(&self) -> Message {
+        Message::new(Command::NICK, vec![self.primary.clone()])
+    }
+
+    /// Handles a message the server sent, returning anything that should be sent in response.
+    /// `state` is what was known before the message, including our nick.
+    /// Fails if registration can't go on because every nick was refused.
+    pub fn handle(
+        &mut self,
+        msg: &Message,
+        state: &State,
+        now: Instant,
+    ) -> Result<Vec<Message>, String> {
+        let casemapping = state.support().casemapping;
+        let is_primary = |nick: &str| casemapping.same(nick, &self.primary);
+        match msg.command {
+            Command::ERR_NICKNAMEINUSE
+            | Command::ERR_ERRONEUSNICKNAME
+            | Command::ERR_NICKCOLLISION
+            | Command::ERR_UNAVAILRESOURCE
+                if !self.registered =>
+            {
+                let Some(nick) = self.fallbacks.get(self.tried) else {
+                    return Err(format!(
+                        "every nick was refused: {}",
+                        msg.params.last().map_or("", |p| p.as_str())
+                    ));
+                };
+                self.tried += 1;
+                info!("Nick refused, trying {}", nick);
+                return Ok(vec![Message::new(Command::NICK, vec![nick.clone()])]);
+            }
+            // the server will never let us have it
+            Command::ERR_ERRONEUSNICKNAME if msg.params.get(1).is_some_and(|n| is_primary(n)) => {
+                self.regain = Regain::Idle;
+            }
+            Command::RPL_WELCOME => self.registered = true,
+            // ISUPPORT has all arrived by the end of the MOTD, so we know if MONITOR is there
+            Command::RPL_ENDOFMOTD | Command::ERR_NOMOTD
+                if !is_primary(state.me()) && self.regain == Regain::Idle =>
+            {
+                return Ok(self.watch(state, now));
+            }
+            Command::NICK if !msg.params.is_empty() => {
+                let from_me = msg
+                    .source
+                    .as_ref()
+                    .and_then(|s| s.nick())
+                    .is_some_and(|nick| state.is_me(nick));
+                if !from_me || !self.registered {
+                    return Ok(vec![]);
+                }
+                if is_primary(&msg.params[0]) {
+                    let watching = std::mem::replace(&mut self.regain, Regain::Idle);
+                    if watching == Regain::Monitor {
+                        return Ok(vec![self.monitor("-")]);
+                    }
+                } else if self.regain == Regain::Idle {
+                    // the server, or services, moved us off our nick
+                    return Ok(self.watch(state, now));
+                }
+            }
+            Command::RPL_MONOFFLINE if self.regain == Regain::Monitor => {
+                let offline = msg.params.get(1).map_or("", |p| p.as_str());
+                // targets may be full masks
+                if offline
+                    .split(',')
+                    .any(|t| is_primary(t.split('!').next().unwrap_or(t)))
+                {
+                    return Ok(vec![self.start()]);
+                }
+            }
+            Command::RPL_ISON if matches!(self.regain, Regain::Ison(_)) => {
+                let online = msg.params.get(1).map_or("", |p| p.as_str());
+                if !online.split(' ').any(is_primary) {
+                    return Ok(vec![self.start()]);
+                }
+            }
+            _ => {}
+        }
+        Ok(vec![])
+    }
+
+    /// Returns an ISON to send if it's time to check on our nick again
+    pub fn poll(&mut self, now: Instant) -> Option<Message> {
+        match self.regain {
+            Regain::Ison(due) if now >= due => {
+                self.regain = Regain::Ison(now + ISON_INTERVAL);
+                Some(Message::new(Command::ISON, vec![self.primary.clone()]))
+            }
+            _ => None,
+        }
+    }
+
+    /// How long until `poll` has something to send, if it ever will
+    pub fn read_timeout(&self, now: Instant) -> Option<Duration> {
+        match self.regain {
+            Regain::Ison(due) => Some(
+                due.saturating_duration_since(now)
+                    .max(Duration::from_millis(1)),
+            ),
+            _ => None,
+        }
+    }
+
+    // starts watching for the primary nick to come free
+    fn watch(&mut self, state: &State, now: Instant) -> Vec<Message> {
+        if state.support().monitor.is_some() {
+            self.regain = Regain::Monitor;
+            vec![self.monitor("+")]
+        } else {
+            self.regain = Regain::Ison(now);
+            vec![]
+        }
+    }
+
+    fn monitor(&self, op: &str) -> Message {
+        Message::new(Command::MONITOR, vec![op.to_string(), self.primary.clone()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::parse_message;
+
+    fn handle(r: &mut Recovery, state: &mut State, line: &str, now: Instant) -> Vec<String> {
+        let msg = parse_message(line).unwrap();
+        let out = r.handle(&msg, state, now).unwrap();
+        state.update(&msg, std::time::UNIX_EPOCH);
+        out.iter().map(|m| m.serialize().unwrap()).collect()
+    }
+
+    #[test]
+    fn falls_back_while_registering() {
+        let mut r = Recovery::new("ircrab", &["crab".to_string()]);
+        let mut state = State::new("ircrab");
+        let now = Instant::now();
+        assert_eq!(r.start().params, vec!["ircrab"]);
+        let taken = ":srv 433 * ircrab :Nickname is already in use";
+        assert_eq!(
+            handle(&mut r, &mut state, taken, now),
+            vec!["NICK crab\r\n"]
+        );
+        assert_eq!(
+            handle(&mut r, &mut state, taken, now),
+            vec!["NICK ircrab_\r\n"]
+        );
+        let bad = ":srv 432 * ircrab_ :Erroneous Nickname";
+        assert_eq!(
+            handle(&mut r, &mut state, bad, now),
+            vec!["NICK ircrab1\r\n"]
+        );
+        for i in 2..=NUMBERED {
+            assert_eq!(
+                handle(&mut r, &mut state, taken, now),
+                vec![format!("NICK ircrab{}\r\n", i)]
+            );
+        }
+        assert!(r
+            .handle(&parse_message(taken).unwrap(), &state, now)
+            .is_err());
+    }
+
+    #[test]
+    fn regains_with_ison() {
+        let mut r = Recovery::new("ircrab", &[]);
+        let mut state = State::new("ircrab");
+        let now = Instant::now();
+        handle(&mut r, &mut state, ":srv 433 * ircrab :in use", now);
+        handle(&mut r, &mut state, ":srv 001 ircrab_ :Welcome", now);
+        assert_eq!(r.read_timeout(now), None);
+        handle(&mut r, &mut state, ":srv 376 ircrab_ :End of /MOTD", now);
+
+        assert_eq!(r.read_timeout(now), Some(Duration::from_millis(1)));
+        assert_eq!(r.poll(now).unwrap().params, vec!["ircrab"]);
+        assert_eq!(r.poll(now), None);
+        assert_eq!(r.read_timeout(now), Some(ISON_INTERVAL));
+        // still taken
+        let out = handle(&mut r, &mut state, ":srv 303 ircrab_ :IRCrab", now);
+        assert!(out.is_empty());
+        assert!(r.poll(now + ISON_INTERVAL).is_some());
+        let out = handle(&mut r, &mut state, ":srv 303 ircrab_ :", now);
+        assert_eq!(out, vec!["NICK ircrab\r\n"]);
+        // a late 433 doesn't start registering all over again
+        let out = handle(&mut r, &mut state, ":srv 433 ircrab_ ircrab :in use", now);
+        assert!(out.is_empty());
+
+        handle(&mut r, &mut state, ":ircrab_!u@h NICK ircrab", now);
+        assert_eq!(state.me(), "ircrab");
+        assert_eq!(r.read_timeout(now), None);
+        assert_eq!(r.poll(now + ISON_INTERVAL * 2), None);
+
+        // services renamed us, so go after it again
+        handle(&mut r, &mut state, ":ircrab!u@h NICK Guest42", now);
+        assert!(r.poll(now).is_some());
+    }
+
+    #[test]
+    fn regains_with_monitor() {
+        let mut r = Recovery::new("ircrab", &[]);
+        let mut state = State::new("ircrab");
+        let now = Instant::now();
+        handle(&mut r, &mut state, ":srv 433 * ircrab :in use", now);
+        handle(&mut r, &mut state, ":srv 001 crab :Welcome", now);
+        handle(
+            &mut r,
+            &mut state,
+            ":srv 005 crab MONITOR=100 :are supported",
+            now,
+        );
+        let out = handle(&mut r, &mut state, ":srv 376 crab :End of /MOTD", now);
+        assert_eq!(out, vec!["MONITOR + ircrab\r\n"]);
+        assert_eq!(r.read_timeout(now), None);
+        let out = handle(&mut r, &mut state, ":srv 731 crab :someone,IRCrab!u@h", now);
+        assert_eq!(out, vec!["NICK ircrab\r\n"]);
+        let out = handle(&mut r, &mut state, ":crab!u@h NICK ircrab", now);
+        assert_eq!(out, vec!["MONITOR - ircrab\r\n"]);
+        assert_eq!(r.regain, Regain::Idle);
+    }
+}
@@ -0,0 +1,20 @@
+use crate::irc::{Command, Message};
+use crate::secret::Secret;
+
+/// Logging in to services by messaging NickServ, for networks without SASL
+#[derive(Clone, PartialEq, Eq)]
+pub struct NickServ {
+    // the account to identify to, if it isn't the nick we have
+    pub account: Option<String>,
+    pub password: Secret,
+}
+
+impl NickServ {
+    pub fn identify(&self) -> Message {
+        let text = match &self.account {
+            Some(account) => format!("IDENTIFY {} {}", account, self.password.expose()),
+            None => format!("IDENTIFY {}", self.password.expose()),
+        };
+        Message::new(Command::PRIVMSG, vec!["NickServ".to_string(), text])
+    }
+}
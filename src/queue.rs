@@ -0,0 +1,507 @@
+use crate::casemap::{CaseMapping, Folded};
+use crate::irc::{Command, Message, Source};
+use crate::isupport::ISupport;
+use crate::split::{self, Splitting};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::mpsc::{RecvError, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How urgently a message needs to go out. Higher priorities are always sent first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // keeps the connection alive, so it isn't held back by flood control either
+    Critical,
+    // replies and commands someone is waiting on
+    Interactive,
+    // anything that can wait, or be dropped if too much of it piles up
+    Bulk,
+}
+
+impl Priority {
+    /// The priority a message gets when it's sent without one
+    pub fn of(msg: &Message) -> Priority {
+        match msg.command {
+            Command::PING | Command::PONG | Command::QUIT => Priority::Critical,
+            _ => Priority::Interactive,
+        }
+    }
+}
+
+// messages to the same target are sent in order, but targets take turns
+fn target(msg: &Message, casemapping: CaseMapping) -> Folded {
+    let target = match msg.command {
+        Command::PRIVMSG | Command::NOTICE | Command::MODE | Command::KICK | Command::TOPIC => {
+            msg.params.first().map(|t| t.as_str()).unwrap_or("")
+        }
+        _ => "",
+    };
+    casemapping.fold(target)
+}
+
+// A single priority class: a queue per target, served round-robin.
+// Messages carry a sequence number so the oldest one can be found across targets.
+#[derive(Default)]
+struct Class {
+    targets: VecDeque<(Folded, VecDeque<(u64, Message)>)>,
+    len: usize,
+}
+
+impl Class {
+    fn push(&mut self, seq: u64, key: Folded, msg: Message) {
+        match self.targets.iter_mut().find(|(t, _)| *t == key) {
+            Some((_, queue)) => queue.push_back((seq, msg)),
+            None => self.targets.push_back((key, VecDeque::from([(seq, msg)]))),
+        }
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Message> {
+        let (key, mut queue) = self.targets.pop_front()?;
+        let (_, msg) = queue.pop_front()?;
+        if !queue.is_empty() {
+            // this target had its turn, so it goes to the back of the line
+            self.targets.push_back((key, queue));
+        }
+        self.len -= 1;
+        Some(msg)
+    }
+
+    fn contains(&self, msg: &Message) -> bool {
+        self.targets
+            .iter()
+            .any(|(_, queue)| queue.iter().any(|(_, m)| m == msg))
+    }
+
+    fn drop_oldest(&mut self) -> Option<Message> {
+        let (i, _) = self
+            .targets
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_, queue))| queue.front().map(|(seq, _)| (i, *seq)))
+            .min_by_key(|(_, seq)| *seq)?;
+        let (_, msg) = self.targets[i].1.pop_front()?;
+        if self.targets[i].1.is_empty() {
+            self.targets.remove(i);
+        }
+        self.len -= 1;
+        Some(msg)
+    }
+}
+
+struct State {
+    // indexed by priority
+    classes: [Class; 3],
+    seq: u64,
+    senders: usize,
+    receiver_alive: bool,
+    // the most bulk messages allowed to wait at once
+    bulk_limit: usize,
+    splitting: Splitting,
+    // our own prefix as the server relays it, which counts against the length of what we send
+    source: Option<Source>,
+    // how the server folds targets, so that one target isn't given two turns
+    casemapping: CaseMapping,
+    // the longest line the server accepts
+    line_len: usize,
+    // whether the server accepts client-only tags, which are stripped until it does
+    client_tags: bool,
+}
+
+impl State {
+    fn pop(&mut self, max: Priority) -> Option<(Message, Priority)> {
+        let (mut msg, priority) = [Priority::Critical, Priority::Interactive, Priority::Bulk]
+            .into_iter()
+            .filter(|p| *p <= max)
+            .find_map(|p| self.classes[p as usize].pop().map(|m| (m, p)))?;
+        // clients may only send client-only tags, and only once the server has agreed to
+        // accept them
+        let client_tags = self.client_tags;
+        msg.tags.retain(|k, _| client_tags && k.starts_with('+'));
+        Some((msg, priority))
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// Creates the queue between everything that sends messages and the writer.
+/// It works like an mpsc channel, except that messages come out by priority and
+/// then round-robin between targets instead of in the order they went in.
+/// Text that's too long for one PRIVMSG or NOTICE is split across several.
+pub fn channel(bulk_limit: usize, splitting: Splitting) -> (Sender, Receiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            classes: Default::default(),
+            seq: 0,
+            senders: 1,
+            receiver_alive: true,
+            bulk_limit,
+            splitting,
+            source: None,
+            casemapping: CaseMapping::Rfc1459,
+            line_len: ISupport::default().linelen,
+            client_tags: false,
+        }),
+        ready: Condvar::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+impl Sender {
+    pub fn send(&self, msg: Message) -> Result<(), SendErr> {
+        let priority = Priority::of(&msg);
+        self.send_with(msg, priority)
+    }
+
+    pub fn send_with(&self, msg: Message, priority: Priority) -> Result<(), SendErr> {
+        let mut state = self.shared.lock();
+        if !state.receiver_alive {
+            return Err(SendErr);
+        }
+        for msg in state.split(msg) {
+            state.push(msg, priority);
+        }
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Record our prefix once the server has told us what it is, so that messages are split
+    /// to fit once it has been added
+    pub fn set_source(&self, source: Source) {
+        self.shared.lock().source = Some(source);
+    }
+
+    /// Record how the server folds the case of targets and how long its lines can be,
+    /// once it has said
+    pub fn set_support(&self, support: &ISupport) {
+        let mut state = self.shared.lock();
+        state.casemapping = support.casemapping;
+        state.line_len = support.linelen;
+    }
+
+    /// Record whether the server has agreed to accept client-only tags
+    pub fn set_client_tags(&self, enabled: bool) {
+        self.shared.lock().client_tags = enabled;
+    }
+}
+
+impl State {
+    fn split(&self, msg: Message) -> Vec<Message> {
+        match msg.command {
+            Command::PRIVMSG | Command::NOTICE if msg.params.len() == 2 => {
+                let budget = split::budget(
+                    self.source.as_ref(),
+                    &msg.command,
+                    &msg.params[0],
+                    self.line_len,
+                );
+                split::split(&msg.params[1], budget, &self.splitting)
+                    .into_iter()
+                    .map(|text| Message {
+                        params: vec![msg.params[0].clone(), text],
+                        ..msg.clone()
+                    })
+                    .collect()
+            }
+            _ => vec![msg],
+        }
+    }
+
+    fn push(&mut self, msg: Message, priority: Priority) {
+        if priority == Priority::Bulk {
+            let bulk = &mut self.classes[Priority::Bulk as usize];
+            // an identical message is already on its way
+            if bulk.contains(&msg) {
+                return;
+            }
+            if bulk.len >= self.bulk_limit {
+                if let Some(dropped) = bulk.drop_oldest() {
+                    warn!("Outgoing queue is full, dropping {:?}", dropped.command);
+                }
+            }
+        }
+        self.seq += 1;
+        let key = target(&msg, self.casemapping);
+        self.classes[priority as usize].push(self.seq, key, msg);
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.shared.lock().senders -= 1;
+        self.shared.ready.notify_all();
+    }
+}
+
+// The receiving end is gone, so the message was dropped
+#[derive(Debug)]
+pub struct SendErr;
+
+impl fmt::Display for SendErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the outgoing queue is closed")
+    }
+}
+
+pub struct Receiver {
+    shared: Arc<Shared>,
+}
+
+impl Receiver {
+    /// The longest line the server accepts, which messages must be serialized to fit
+    pub fn line_len(&self) -> usize {
+        self.shared.lock().line_len
+    }
+
+    /// Blocks until a message is available. Fails once every sender is gone and the queue is empty.
+    pub fn recv(&self) -> Result<(Message, Priority), RecvError> {
+        self.wait(None, Priority::Bulk).map_err(|_| RecvError)
+    }
+
+    /// Like `recv`, but gives up after the timeout, and only considers messages of
+    /// at least the given priority
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+        max: Priority,
+    ) -> Result<(Message, Priority), RecvTimeoutError> {
+        self.wait(Some(Instant::now() + timeout), max)
+    }
+
+    fn wait(
+        &self,
+        deadline: Option<Instant>,
+        max: Priority,
+    ) -> Result<(Message, Priority), RecvTimeoutError> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(next) = state.pop(max) {
+                return Ok(next);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            state = match deadline {
+                None => self.shared.ready.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    self.shared
+                        .ready
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0
+                }
+            };
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.shared.lock().receiver_alive = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn privmsg(target: &str, text: &str) -> Message {
+        Message::new(Command::PRIVMSG, vec![target.to_string(), text.to_string()])
+    }
+
+    fn splitting() -> Splitting {
+        Splitting {
+            max_lines: 3,
+            marker: "(continued)".to_string(),
+        }
+    }
+
+    fn recv_all(rx: &Receiver) -> Vec<String> {
+        let mut out = vec![];
+        while let Ok((msg, _)) = rx.recv_timeout(Duration::ZERO, Priority::Bulk) {
+            out.push(msg.params.join(" "));
+        }
+        out
+    }
+
+    #[test]
+    fn sends_by_priority() {
+        let (tx, rx) = channel(10, splitting());
+        tx.send_with(privmsg("#a", "bulk"), Priority::Bulk).unwrap();
+        tx.send(privmsg("#a", "reply")).unwrap();
+        tx.send(Message::new(Command::PONG, vec!["pong".to_string()]))
+            .unwrap();
+        assert_eq!(recv_all(&rx), vec!["pong", "#a reply", "#a bulk"]);
+    }
+
+    #[test]
+    fn round_robins_between_targets() {
+        let (tx, rx) = channel(10, splitting());
+        for i in 0..3 {
+            tx.send(privmsg("#chatty", &i.to_string())).unwrap();
+        }
+        tx.send(privmsg("#quiet", "hi")).unwrap();
+        tx.send(privmsg("someone", "hey")).unwrap();
+        assert_eq!(
+            recv_all(&rx),
+            vec![
+                "#chatty 0",
+                "#quiet hi",
+                "someone hey",
+                "#chatty 1",
+                "#chatty 2"
+            ]
+        );
+
+        // the server sees these as the same channel, so they keep their order
+        tx.send(privmsg("#Chatty", "0")).unwrap();
+        tx.send(privmsg("#chatty", "1")).unwrap();
+        tx.send(privmsg("[Someone]", "hey")).unwrap();
+        tx.send(privmsg("{someone}", "again")).unwrap();
+        assert_eq!(
+            recv_all(&rx),
+            vec!["#Chatty 0", "[Someone] hey", "#chatty 1", "{someone} again"]
+        );
+        // but not by ascii rules
+        tx.set_support(&ISupport {
+            casemapping: CaseMapping::Ascii,
+            ..ISupport::default()
+        });
+        tx.send(privmsg("[someone]", "hey")).unwrap();
+        tx.send(privmsg("[someone]", "again")).unwrap();
+        tx.send(privmsg("{someone}", "hi")).unwrap();
+        assert_eq!(
+            recv_all(&rx),
+            vec!["[someone] hey", "{someone} hi", "[someone] again"]
+        );
+    }
+
+    #[test]
+    fn strips_tags_until_accepted() {
+        let (tx, rx) = channel(10, splitting());
+        let mut msg = privmsg("#a", "hi");
+        msg.tags
+            .insert("+draft/reply".to_string(), "abc".to_string());
+        msg.tags.insert("time".to_string(), "now".to_string());
+        tx.send(msg.clone()).unwrap();
+        assert!(rx.recv().unwrap().0.tags.is_empty());
+        tx.set_client_tags(true);
+        tx.send(msg).unwrap();
+        let tags = rx.recv().unwrap().0.tags;
+        assert_eq!(tags.keys().collect::<Vec<_>>(), vec!["+draft/reply"]);
+    }
+
+    #[test]
+    fn coalesces_and_drops_bulk() {
+        let (tx, rx) = channel(3, splitting());
+        tx.send_with(privmsg("#a", "1"), Priority::Bulk).unwrap();
+        tx.send_with(privmsg("#b", "2"), Priority::Bulk).unwrap();
+        tx.send_with(privmsg("#a", "1"), Priority::Bulk).unwrap();
+        tx.send_with(privmsg("#a", "3"), Priority::Bulk).unwrap();
+        tx.send_with(privmsg("#c", "4"), Priority::Bulk).unwrap();
+        // the duplicate was coalesced, and the oldest was dropped to make room for the last
+        assert_eq!(recv_all(&rx), vec!["#a 3", "#b 2", "#c 4"]);
+    }
+
+    #[test]
+    fn filters_by_priority() {
+        let (tx, rx) = channel(10, splitting());
+        tx.send(privmsg("#a", "reply")).unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(10), Priority::Critical)
+                .unwrap_err(),
+            RecvTimeoutError::Timeout
+        );
+        tx.send(Message::new(Command::QUIT, vec![])).unwrap();
+        let (msg, priority) = rx
+            .recv_timeout(Duration::from_millis(10), Priority::Critical)
+            .unwrap();
+        assert_eq!(msg.command, Command::QUIT);
+        assert_eq!(priority, Priority::Critical);
+    }
+
+    #[test]
+    fn splits_long_messages() {
+        let (tx, rx) = channel(10, splitting());
+        let text = "lorem ipsum ".repeat(100);
+        tx.send(privmsg("#a", &text)).unwrap();
+        tx.send(privmsg("#b", "short")).unwrap();
+        let lines = recv_all(&rx);
+        assert_eq!(lines.len(), 4);
+        // the pieces of a long message don't hold up other targets
+        assert_eq!(lines[1], "#b short");
+        assert!(lines[0].ends_with(" (continued)"));
+        assert!(lines[3].ends_with(" (continued)"));
+
+        // knowing our prefix is shorter than the worst case makes room for more text
+        let (tx, rx) = channel(10, splitting());
+        let first = |rx: &Receiver| rx.recv().unwrap().0.params[1].len();
+        tx.send(privmsg("#a", &text)).unwrap();
+        let unknown = first(&rx);
+        tx.set_source(Source::parse("ircrab!~ircrab@example.org"));
+        recv_all(&rx);
+        tx.send(privmsg("#a", &text)).unwrap();
+        let known = first(&rx);
+        assert!(known > unknown);
+
+        // and so does a server that takes longer lines
+        tx.set_support(&ISupport {
+            linelen: 1024,
+            ..ISupport::default()
+        });
+        recv_all(&rx);
+        tx.send(privmsg("#a", &text)).unwrap();
+        assert!(first(&rx) > known + 500);
+        assert_eq!(rx.line_len(), 1024);
+    }
+
+    #[test]
+    fn closes_like_a_channel() {
+        let (tx, rx) = channel(10, splitting());
+        let tx2 = tx.clone();
+        let handle = thread::spawn(move || rx.recv().map(|(m, _)| m.params[1].clone()));
+        tx2.send(privmsg("#a", "from a thread")).unwrap();
+        assert_eq!(handle.join().unwrap().unwrap(), "from a thread");
+        // the receiver is gone
+        assert!(tx.send(privmsg("#a", "nobody home")).is_err());
+
+        let (tx, rx) = channel(10, splitting());
+        tx.send(privmsg("#a", "still delivered")).unwrap();
+        drop(tx);
+        assert!(rx.recv().is_ok());
+        assert!(rx.recv().is_err());
+    }
+}
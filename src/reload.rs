@@ -0,0 +1,234 @@
+use crate::casemap::CaseMapping;
+use crate::{Channel, Config};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Where the running config came from, and the flag that asks for it to be read again
+pub struct Source {
+    pub path: PathBuf,
+    // the network picked from it, if not the first
+    pub network: Option<String>,
+    // set from outside, e.g. by a SIGHUP handler
+    pub requested: Arc<AtomicBool>,
+}
+
+impl Source {
+    pub fn new(path: PathBuf, network: Option<String>) -> Source {
+        Source {
+            path,
+            network,
+            requested: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns true once for each time a reload was asked for
+    pub fn take_request(&self) -> bool {
+        self.requested.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// How a freshly loaded config differs from the running one
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    // channels that were added to the config, and should be joined
+    pub joins: Vec<Channel>,
+    // channels that were dropped from the config, and should be parted
+    pub parts: Vec<String>,
+    // the config keys whose changes take effect right away
+    pub applied: Vec<String>,
+    // the config keys whose changes wait for the next connection
+    pub pending: Vec<String>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        *self == Changes::default()
+    }
+
+    /// Describes the changes, a line for each kind
+    pub fn report(&self) -> Vec<String> {
+        if self.is_empty() {
+            return vec!["nothing changed".to_string()];
+        }
+        let mut lines = vec![];
+        if !self.joins.is_empty() {
+            let names: Vec<_> = self.joins.iter().map(|c| c.name.as_str()).collect();
+            lines.push(format!("joining {}", names.join(", ")));
+        }
+        if !self.parts.is_empty() {
+            lines.push(format!("parting {}", self.parts.join(", ")));
+        }
+        if !self.applied.is_empty() {
+            lines.push(format!("applied {}", self.applied.join(", ")));
+        }
+        if !self.pending.is_empty() {
+            lines.push(format!("pending reconnect: {}", self.pending.join(", ")));
+        }
+        lines
+    }
+}
+
+/// Compares the running config with a new one. Channel names are compared by `casemapping`.
+pub fn diff(old: &Config, new: &Config, casemapping: CaseMapping) -> Changes {
+    let keys = |fields: &[(&str, bool)]| -> Vec<String> {
+        fields
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(key, _)| key.to_string())
+            .collect()
+    };
+    let (o, n) = (&old.network, &new.network);
+    let mut pending = keys(&[
+        ("identity.nick", old.my_nick != new.my_nick),
+        ("identity.alt_nicks", old.alt_nicks != new.alt_nicks),
+        ("identity.username", old.username != new.username),
+        ("identity.realname", old.realname != new.realname),
+        ("networks[0].host", o.host != n.host),
+        ("networks[0].port", o.port != n.port),
+        ("networks[0].tls", o.ssl != n.ssl || o.tls != n.tls),
+        (
+            "networks[0].encoding",
+            o.encoding.outgoing != n.encoding.outgoing,
+        ),
+        ("networks[0].sasl", old.sasl != new.sasl),
+        ("networks[0].nickserv", old.nickserv != new.nickserv),
+        ("networks[0].pass", old.pass != new.pass),
+        (
+            "connection.capabilities",
+            old.capabilities != new.capabilities,
+        ),
+        ("connection.reconnect", old.reconnect != new.reconnect),
+        ("connection.keepalive", old.keepalive != new.keepalive),
+        ("connection.flood", old.flood != new.flood),
+        (
+            "connection.bulk_queue_limit",
+            old.bulk_queue_limit != new.bulk_queue_limit,
+        ),
+        ("connection.splitting", old.splitting != new.splitting),
+    ]);
+    let applied = keys(&[
+        (
+            "networks[0].fallback_encoding",
+            o.encoding.fallback != n.encoding.fallback,
+        ),
+        (
+            "triggers.heartbeat",
+            old.triggers.heartbeat != new.triggers.heartbeat,
+        ),
+        (
+            "triggers.on_connect",
+            old.triggers.on_connect != new.triggers.on_connect,
+        ),
+        (
+            "triggers.reload",
+            old.triggers.reload != new.triggers.reload,
+        ),
+        ("triggers.modes", old.triggers.modes != new.triggers.modes),
+    ]);
+
+    let find = |channels: &'_ [Channel], name: &str| {
+        channels
+            .iter()
+            .find(|c| casemapping.same(&c.name, name))
+            .cloned()
+    };
+    let mut joins = vec![];
+    for (i, channel) in n.channels.iter().enumerate() {
+        match find(&o.channels, &channel.name) {
+            None => joins.push(channel.clone()),
+            // a key is only sent when joining
+            Some(was) if was.key != channel.key => {
+                pending.push(format!("networks[0].channels[{}].key", i))
+            }
+            Some(_) => {}
+        }
+    }
+    let parts = o
+        .channels
+        .iter()
+        .filter(|c| find(&n.channels, &c.name).is_none())
+        .map(|c| c.name.clone())
+        .collect();
+    Changes {
+        joins,
+        parts,
+        applied,
+        pending,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn parse(extra: &str, channels: &str) -> Config {
+        config::parse(&format!(
+            "[identity]\nnick = \"ircrab\"\n{}\n[[networks]]\nname = \"libera\"\nhost = \"irc.libera.chat\"\nchannels = [{}]\n",
+            extra, channels
+        ), None)
+        .unwrap()
+    }
+
+    #[test]
+    fn finds_nothing_to_do() {
+        let cfg = parse("", "{ name = \"#cwru\" }");
+        let changes = diff(
+            &cfg,
+            &parse("", "{ name = \"#CWRU\" }"),
+            CaseMapping::Rfc1459,
+        );
+        assert!(changes.is_empty());
+        assert_eq!(changes.report(), vec!["nothing changed"]);
+    }
+
+    #[test]
+    fn diffs_channels_and_triggers() {
+        let old = parse("", "{ name = \"#cwru\" }, { name = \"#rust\" }");
+        let new = parse(
+            "[triggers.heartbeat]\ncommand = \"!hb\"",
+            "{ name = \"#Rust\", key = \"hunter2\" }, { name = \"#new\" }",
+        );
+        let changes = diff(&old, &new, CaseMapping::Rfc1459);
+        assert_eq!(
+            changes.joins,
+            vec![Channel {
+                name: "#new".to_string(),
+                key: None
+            }]
+        );
+        assert_eq!(changes.parts, vec!["#cwru"]);
+        assert_eq!(changes.applied, vec!["triggers.heartbeat"]);
+        assert_eq!(changes.pending, vec!["networks[0].channels[0].key"]);
+        assert_eq!(
+            changes.report(),
+            vec![
+                "joining #new",
+                "parting #cwru",
+                "applied triggers.heartbeat",
+                "pending reconnect: networks[0].channels[0].key",
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_connection_changes_pending() {
+        let old = parse("", "");
+        let mut new = parse("username = \"crab\"", "");
+        new.network.host = "irc.example.org".to_string();
+        new.network.tls.accept_invalid_certs = true;
+        new.flood.burst = 10;
+        let changes = diff(&old, &new, CaseMapping::Ascii);
+        assert_eq!(
+            changes.pending,
+            vec![
+                "identity.username",
+                "networks[0].host",
+                "networks[0].tls",
+                "connection.flood"
+            ]
+        );
+        assert!(changes.applied.is_empty());
+    }
+}
@@ -0,0 +1,314 @@
+use crate::irc::{Command, Message};
+use crate::secret::Secret;
+use std::fmt;
+use zeroize::Zeroizing;
+
+// AUTHENTICATE payloads are sent in chunks of at most this many bytes
+const CHUNK_LEN: usize = 400;
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum Mechanism {
+    Plain { username: String, password: Secret },
+    // authenticate with the TLS client certificate
+    External,
+}
+
+impl Mechanism {
+    fn name(&self) -> &'static str {
+        match self {
+            Mechanism::Plain { .. } => "PLAIN",
+            Mechanism::External => "EXTERNAL",
+        }
+    }
+
+    fn payload(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(match self {
+            // authzid \0 authcid \0 passwd
+            Mechanism::Plain { username, password } => {
+                format!("{}\0{}\0{}", username, username, password.expose()).into_bytes()
+            }
+            Mechanism::External => vec![],
+        })
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum State {
+    // waiting for the server to acknowledge the sasl capability
+    Idle,
+    // AUTHENTICATE <mechanism> has been sent
+    Started,
+    Succeeded,
+}
+
+/// Drives SASL authentication during registration.
+/// See https://ircv3.net/specs/extensions/sasl-3.1
+pub struct Authenticator {
+    mechanism: Mechanism,
+    state: State,
+    // set once the sasl capability shows up in CAP LS
+    offered: bool,
+}
+
+impl Authenticator {
+    pub fn new(mechanism: Mechanism) -> Authenticator {
+        Authenticator {
+            mechanism,
+            state: State::Idle,
+            offered: false,
+        }
+    }
+
+    /// Returns true while the exchange is underway and registration must be held open
+    pub fn in_progress(&self) -> bool {
+        self.state == State::Started
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.state == State::Succeeded
+    }
+
+    /// Process an incoming message, returning any messages that should be sent in response.
+    /// An error means authentication can't succeed and the connection should be abandoned.
+    pub fn handle(&mut self, msg: &Message) -> Result<Vec<Message>, SaslErr> {
+        match msg.command {
+            Command::CAP if msg.params.len() >= 3 => self.handle_cap(msg),
+            Command::AUTHENTICATE if self.state == State::Started => {
+                if msg.params.first().map(|p| p.as_str()) != Some("+") {
+                    // neither mechanism supports a server challenge
+                    return Ok(vec![authenticate("*")]);
+                }
+                Ok(encode_payload(&self.mechanism.payload()))
+            }
+            Command::RPL_LOGGEDIN => {
+                info!("Logged in: {}", msg.params.last().unwrap_or(&String::new()));
+                Ok(vec![])
+            }
+            Command::RPL_SASLSUCCESS | Command::ERR_SASLALREADY => {
+                self.state = State::Succeeded;
+                Ok(vec![])
+            }
+            Command::RPL_SASLMECHS => Err(self.fail(&format!(
+                "server only supports mechanisms {}",
+                msg.params.get(1).unwrap_or(&String::new())
+            ))),
+            Command::ERR_NICKLOCKED
+            | Command::ERR_SASLFAIL
+            | Command::ERR_SASLTOOLONG
+            | Command::ERR_SASLABORTED => {
+                Err(self.fail(msg.params.last().map_or("", |p| p.as_str())))
+            }
+            Command::RPL_WELCOME if !self.succeeded() => {
+                Err(self.fail("server completed registration without SASL"))
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    fn handle_cap(&mut self, msg: &Message) -> Result<Vec<Message>, SaslErr> {
+        let more = msg.params.len() > 3 && msg.params[2] == "*";
+        let mut caps = msg.params.last().unwrap().split(' ');
+        match msg.params[1].as_str() {
+            "LS" => {
+                if let Some(sasl) = caps.find(|c| *c == "sasl" || c.starts_with("sasl=")) {
+                    self.offered = true;
+                    // a value is the list of supported mechanisms
+                    if let Some((_, mechs)) = sasl.split_once('=') {
+                        if !mechs.split(',').any(|m| m == self.mechanism.name()) {
+                            return Err(
+                                self.fail(&format!("server only supports mechanisms {}", mechs))
+                            );
+                        }
+                    }
+                }
+                if !more && !self.offered {
+                    return Err(self.fail("server does not support SASL"));
+                }
+                Ok(vec![])
+            }
+            "ACK" if self.state == State::Idle && caps.any(|c| c == "sasl") => {
+                self.state = State::Started;
+                Ok(vec![authenticate(self.mechanism.name())])
+            }
+            "NAK" if caps.any(|c| c == "sasl") => {
+                Err(self.fail("server refused the sasl capability"))
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    fn fail(&mut self, reason: &str) -> SaslErr {
+        self.state = State::Idle;
+        SaslErr {
+            mechanism: self.mechanism.name(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+fn authenticate(param: &str) -> Message {
+    Message::new(Command::AUTHENTICATE, vec![param.to_string()])
+}
+
+// base64 encodes the payload and splits it into AUTHENTICATE lines.
+// a payload that is empty or an exact multiple of the chunk length is terminated with '+'
+fn encode_payload(payload: &[u8]) -> Vec<Message> {
+    let encoded = Zeroizing::new(base64(payload));
+    let mut out: Vec<Message> = encoded
+        .as_bytes()
+        .chunks(CHUNK_LEN)
+        // base64 output is always ascii, so chunks are always valid utf-8
+        .map(|c| authenticate(std::str::from_utf8(c).unwrap()))
+        .collect();
+    if encoded.len().is_multiple_of(CHUNK_LEN) {
+        out.push(authenticate("+"));
+    }
+    out
+}
+
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone)]
+pub struct SaslErr {
+    mechanism: &'static str,
+    reason: String,
+}
+
+impl fmt::Display for SaslErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SASL {} authentication failed: {}",
+            self.mechanism, self.reason
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::parse_message;
+
+    fn handle(a: &mut Authenticator, line: &str) -> Result<Vec<String>, SaslErr> {
+        a.handle(&parse_message(line).unwrap())
+            .map(|msgs| msgs.into_iter().map(|m| m.params.join(" ")).collect())
+    }
+
+    fn plain(password: &str) -> Authenticator {
+        Authenticator::new(Mechanism::Plain {
+            username: "ircrab".to_string(),
+            password: Secret::new(password.to_string()),
+        })
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(b"\0\xff\xfe"), "AP/+");
+    }
+
+    #[test]
+    fn authenticates_with_plain() {
+        let mut a = plain("hunter2");
+        assert!(handle(&mut a, ":srv CAP * LS :sasl=PLAIN,EXTERNAL")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            handle(&mut a, ":srv CAP * ACK :server-time sasl").unwrap(),
+            vec!["PLAIN"]
+        );
+        assert!(a.in_progress());
+        assert_eq!(
+            handle(&mut a, "AUTHENTICATE +").unwrap(),
+            vec![base64(b"ircrab\0ircrab\0hunter2")]
+        );
+        handle(
+            &mut a,
+            ":srv 900 ircrab ircrab!ircrab@host ircrab :You are now logged in",
+        )
+        .unwrap();
+        handle(&mut a, ":srv 903 ircrab :SASL authentication successful").unwrap();
+        assert!(a.succeeded());
+        assert!(!a.in_progress());
+    }
+
+    #[test]
+    fn authenticates_with_external() {
+        let mut a = Authenticator::new(Mechanism::External);
+        handle(&mut a, ":srv CAP * LS :sasl").unwrap();
+        assert_eq!(
+            handle(&mut a, ":srv CAP * ACK :sasl").unwrap(),
+            vec!["EXTERNAL"]
+        );
+        assert_eq!(handle(&mut a, "AUTHENTICATE +").unwrap(), vec!["+"]);
+    }
+
+    #[test]
+    fn chunks_long_payloads() {
+        // 300 bytes encodes to exactly 400, so a trailing '+' is required
+        let chunks = encode_payload(&[b'a'; 300]);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].params[0].len(), 400);
+        assert_eq!(chunks[1].params[0], "+");
+
+        let chunks = encode_payload(&[b'a'; 301]);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].params[0].len(), 4);
+    }
+
+    #[test]
+    fn fails_on_bad_credentials() {
+        let mut a = plain("wrong");
+        handle(&mut a, ":srv CAP * LS :sasl").unwrap();
+        handle(&mut a, ":srv CAP * ACK :sasl").unwrap();
+        handle(&mut a, "AUTHENTICATE +").unwrap();
+        let err = handle(&mut a, ":srv 904 ircrab :SASL authentication failed").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "SASL PLAIN authentication failed: SASL authentication failed"
+        );
+        assert!(!a.in_progress());
+
+        // a bare numeric is still a failure, not a panic
+        let mut a = plain("wrong");
+        handle(&mut a, ":srv CAP * ACK :sasl").unwrap();
+        let err = handle(&mut a, ":srv 904").unwrap_err();
+        assert_eq!(err.to_string(), "SASL PLAIN authentication failed: ");
+        assert!(!a.in_progress());
+    }
+
+    #[test]
+    fn fails_without_server_support() {
+        let mut a = plain("hunter2");
+        assert!(handle(&mut a, ":srv CAP * LS * :sasl").is_ok());
+        let mut a = plain("hunter2");
+        assert!(handle(&mut a, ":srv CAP * LS :server-time").is_err());
+        let mut a = Authenticator::new(Mechanism::External);
+        assert!(handle(&mut a, ":srv CAP * LS :sasl=PLAIN").is_err());
+        let mut a = plain("hunter2");
+        assert!(handle(&mut a, ":srv 001 ircrab :Welcome").is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::irc::{Command, Message};
+use once_cell::sync::Lazy;
+use std::borrow::Cow;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::{Arc, RwLock, Weak};
+use std::{env, fmt, fs};
+use zeroize::Zeroizing;
+
+const REDACTED: &str = "<redacted>";
+
+// AUTHENTICATE parameters that aren't part of a payload
+const SASL_CONTROL: [&str; 4] = ["+", "*", "PLAIN", "EXTERNAL"];
+
+// NickServ commands that carry a password
+const NICKSERV_COMMANDS: [&str; 6] = [
+    "IDENTIFY", "REGISTER", "GHOST", "RECOVER", "RELEASE", "REGAIN",
+];
+
+// every secret that's still alive, so that they can be scrubbed from log lines. These are
+// weak handles rather than copies, so a secret is wiped from memory as soon as it's dropped.
+static KNOWN: Lazy<RwLock<Vec<Weak<Zeroizing<String>>>>> = Lazy::new(|| RwLock::new(vec![]));
+
+/// A password or token. It's never logged, and its memory is wiped once it's dropped.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Arc<Zeroizing<String>>);
+
+impl Secret {
+    pub fn new(value: String) -> Secret {
+        let value = Arc::new(Zeroizing::new(value));
+        if !value.is_empty() {
+            let mut known = KNOWN.write().unwrap();
+            known.retain(|k| k.strong_count() > 0);
+            known.push(Arc::downgrade(&value));
+        }
+        Secret(value)
+    }
+
+    /// Reads a secret from a file, leaving off the line break it likely ends with
+    pub fn from_file(path: &Path) -> io::Result<Secret> {
+        let mut file = fs::File::open(path)?;
+        // sized up front, so the buffer isn't reallocated and copies left behind unwiped
+        let len = file.metadata()?.len() as usize;
+        let mut bytes = Zeroizing::new(Vec::with_capacity(len + 1));
+        file.read_to_end(&mut bytes)?;
+        let text = std::str::from_utf8(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Secret::new(text.trim_end_matches(['\r', '\n']).to_string()))
+    }
+
+    pub fn from_env(name: &str) -> Result<Secret, env::VarError> {
+        env::var(name).map(Secret::new)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // the last handle is going, so there's nothing left to look for
+        if Arc::strong_count(&self.0) == 1 {
+            let ours = Arc::as_ptr(&self.0);
+            KNOWN
+                .write()
+                .unwrap()
+                .retain(|k| k.strong_count() > 0 && k.as_ptr() != ours);
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+/// Replaces every secret that appears in a line about to be logged
+pub fn scrub(line: &str) -> Cow<'_, str> {
+    let mut line = Cow::Borrowed(line);
+    for secret in KNOWN.read().unwrap().iter().filter_map(Weak::upgrade) {
+        if line.contains(secret.as_str()) {
+            line = Cow::Owned(line.replace(secret.as_str(), REDACTED));
+        }
+    }
+    line
+}
+
+/// A copy of the message fit for logging, if it carries credentials. Unlike `scrub`, this
+/// catches them when they're encoded, like in a SASL payload.
+pub fn redact(msg: &Message) -> Option<Message> {
+    let mut params = msg.params.clone();
+    match msg.command {
+        Command::PASS => params.iter_mut().for_each(|p| *p = REDACTED.to_string()),
+        Command::AUTHENTICATE if !params.iter().all(|p| SASL_CONTROL.contains(&p.as_str())) => {
+            params = vec![REDACTED.to_string()];
+        }
+        Command::PRIVMSG | Command::NOTICE if params.len() == 2 && is_nickserv(&params[0]) => {
+            let command = params[1].split(' ').next().unwrap_or("");
+            if !NICKSERV_COMMANDS
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(command))
+            {
+                return None;
+            }
+            params[1] = format!("{} {}", command, REDACTED);
+        }
+        _ => return None,
+    }
+    let mut redacted = Message::new(msg.command.clone(), params);
+    redacted.tags = msg.tags.clone();
+    redacted.source = msg.source.clone();
+    Some(redacted)
+}
+
+// NickServ, or its full address like NickServ@services.libera.chat
+fn is_nickserv(target: &str) -> bool {
+    let nick = target.split('@').next().unwrap_or(target);
+    nick.eq_ignore_ascii_case("NickServ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::parse_message;
+
+    fn redacted(line: &str) -> Option<String> {
+        redact(&parse_message(line).unwrap()).map(|m| m.serialize().unwrap())
+    }
+
+    #[test]
+    fn scrubs_known_secrets() {
+        let secret = Secret::new("correct horse battery staple".to_string());
+        assert_eq!(
+            scrub(
+                "PRIVMSG #cwru :correct horse battery staple, twice: correct horse battery staple"
+            ),
+            "PRIVMSG #cwru :<redacted>, twice: <redacted>"
+        );
+        assert_eq!(scrub("nothing to hide"), "nothing to hide");
+        assert_eq!(format!("{:?}", secret), "Secret(<redacted>)");
+        assert_eq!(secret.expose(), "correct horse battery staple");
+
+        // nothing is kept once the last copy is dropped
+        let copy = secret.clone();
+        drop(secret);
+        assert_eq!(scrub("correct horse battery staple"), "<redacted>");
+        let ours = Arc::as_ptr(&copy.0);
+        drop(copy);
+        assert_eq!(
+            scrub("correct horse battery staple"),
+            "correct horse battery staple"
+        );
+        assert!(!KNOWN.read().unwrap().iter().any(|k| k.as_ptr() == ours));
+    }
+
+    #[test]
+    fn redacts_credentials() {
+        assert_eq!(
+            redacted("PASS hunter2").as_deref(),
+            Some("PASS <redacted>\r\n")
+        );
+        assert_eq!(
+            redacted("AUTHENTICATE aXJjcmFiAGlyY3JhYgBodW50ZXIy").as_deref(),
+            Some("AUTHENTICATE <redacted>\r\n")
+        );
+        assert_eq!(redacted("AUTHENTICATE PLAIN"), None);
+        assert_eq!(redacted("AUTHENTICATE +"), None);
+        assert_eq!(
+            redacted("PRIVMSG NickServ :identify ircrab hunter2").as_deref(),
+            Some("PRIVMSG NickServ :identify <redacted>\r\n")
+        );
+        // echoed back to us, with our prefix
+        assert_eq!(
+            redacted(":ircrab!u@h PRIVMSG NickServ@services. :GHOST crab hunter2").as_deref(),
+            Some(":ircrab!u@h PRIVMSG NickServ@services. :GHOST <redacted>\r\n")
+        );
+        assert_eq!(redacted("PRIVMSG NickServ :INFO ircrab"), None);
+        assert_eq!(redacted("PRIVMSG #cwru :identify yourself"), None);
+    }
+
+    #[test]
+    fn reads_files_and_env() {
+        let path = env::temp_dir().join(format!("ircrab-secret-{}", std::process::id()));
+        fs::write(&path, "s3same\n").unwrap();
+        assert_eq!(Secret::from_file(&path).unwrap().expose(), "s3same");
+        fs::remove_file(&path).unwrap();
+        assert!(Secret::from_file(&path).is_err());
+
+        env::set_var("IRCRAB_TEST_SECRET", "from the env");
+        assert_eq!(
+            Secret::from_env("IRCRAB_TEST_SECRET").unwrap().expose(),
+            "from the env"
+        );
+        assert!(Secret::from_env("IRCRAB_TEST_UNSET").is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use crate::irc::{Command, Source};
+
+/// How long replies are broken up into several messages
+#[derive(Clone, PartialEq, Eq)]
+pub struct Splitting {
+    // the most lines a single reply may take up. anything past that is dropped
+    pub max_lines: usize,
+    // appended to every line that has more text after it
+    pub marker: String,
+}
+
+// worst cases for the parts of our prefix that the server hasn't told us about yet
+const MAX_NICK_LEN: usize = 30;
+// the ident, plus a '~' if it wasn't verified
+const MAX_USER_LEN: usize = 11;
+const MAX_HOST_LEN: usize = 63;
+
+/// How many bytes of text fit in a PRIVMSG or NOTICE once the server has relayed it to
+/// everyone else as `:nick!user@host COMMAND target :text\r\n`, in a line of `line_len` bytes
+pub fn budget(source: Option<&Source>, command: &Command, target: &str, line_len: usize) -> usize {
+    let prefix = match source {
+        Some(Source::User { nick, user, host }) => {
+            nick.len()
+                + 1
+                + user.as_ref().map_or(MAX_USER_LEN, |u| u.len())
+                + 1
+                + host.as_ref().map_or(MAX_HOST_LEN, |h| h.len())
+        }
+        Some(Source::Server(name)) => name.len(),
+        None => MAX_NICK_LEN + 1 + MAX_USER_LEN + 1 + MAX_HOST_LEN,
+    };
+    // ':' prefix ' ' command ' ' target ' :' text "\r\n"
+    let overhead = 1 + prefix + 1 + command.as_str().len() + 1 + target.len() + 2 + 2;
+    line_len.saturating_sub(overhead)
+}
+
+/// Breaks text into lines of at most `budget` bytes, preferring to break between words.
+/// A character or a formatting code is never split across lines.
+pub fn split(text: &str, budget: usize, cfg: &Splitting) -> Vec<String> {
+    let mut lines = vec![];
+    let mut rest = text;
+    while !rest.is_empty() {
+        let last = lines.len() + 1 >= cfg.max_lines.max(1);
+        if rest.len() <= budget {
+            lines.push(rest.to_string());
+            break;
+        }
+        let room = budget.saturating_sub(cfg.marker.len() + 1);
+        let end = break_point(rest, room);
+        lines.push(format!(
+            "{} {}",
+            rest[..end].trim_end_matches(' '),
+            cfg.marker
+        ));
+        rest = rest[end..].trim_start_matches(' ');
+        if last {
+            break;
+        }
+    }
+    lines
+}
+
+// The byte index to end a line at, so that it fits in `room`. This is the last space that
+// fits if there is one, or else as much of the first word as fits. It's always past at least
+// one character so that splitting makes progress.
+fn break_point(text: &str, room: usize) -> usize {
+    let mut fits = 0;
+    let mut space = None;
+    let mut i = 0;
+    while i < text.len() {
+        let end = i + unit_len(&text[i..]);
+        if end > room && fits > 0 {
+            break;
+        }
+        if text[i..].starts_with(' ') && i > 0 {
+            space = Some(i);
+        }
+        fits = end;
+        i = end;
+    }
+    space.unwrap_or(fits)
+}
+
+// The length in bytes of the character or formatting code at the start of the text.
+// Colors take up to two digits for the foreground and background, and hex colors take six,
+// which would change meaning if they were split off from their control character.
+fn unit_len(text: &str) -> usize {
+    let bytes = text.as_bytes();
+    let (is_digit, max): (fn(&u8) -> bool, usize) = match bytes[0] {
+        0x03 => (u8::is_ascii_digit, 2),
+        0x04 => (u8::is_ascii_hexdigit, 6),
+        _ => return text.chars().next().map_or(1, char::len_utf8),
+    };
+    let digits = |from: usize| {
+        bytes[from..]
+            .iter()
+            .take(max)
+            .take_while(|b| is_digit(b))
+            .count()
+    };
+    let mut len = 1 + digits(1);
+    if len > 1 && bytes.get(len) == Some(&b',') {
+        let background = digits(len + 1);
+        if background > 0 {
+            len += 1 + background;
+        }
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(max_lines: usize) -> Splitting {
+        Splitting {
+            max_lines,
+            marker: "(continued)".to_string(),
+        }
+    }
+
+    #[test]
+    fn leaves_short_text_alone() {
+        assert_eq!(split("hello world", 20, &cfg(3)), vec!["hello world"]);
+        assert!(split("", 20, &cfg(3)).is_empty());
+    }
+
+    #[test]
+    fn splits_between_words() {
+        let lines = split("the quick brown fox jumps over the lazy dog", 24, &cfg(10));
+        assert_eq!(
+            lines,
+            vec![
+                "the quick (continued)",
+                "brown fox (continued)",
+                "jumps over the lazy dog"
+            ]
+        );
+        assert!(lines.iter().all(|l| l.len() <= 24));
+    }
+
+    #[test]
+    fn limits_the_number_of_lines() {
+        let lines = split("the quick brown fox jumps over the lazy dog", 24, &cfg(2));
+        assert_eq!(
+            lines,
+            vec!["the quick (continued)", "brown fox (continued)"]
+        );
+    }
+
+    #[test]
+    fn breaks_long_words_on_char_boundaries() {
+        let text = "é".repeat(20);
+        let lines = split(&text, 17, &cfg(10));
+        assert_eq!(lines[0], "éé (continued)");
+        assert_eq!(lines.concat().replace(" (continued)", ""), text);
+    }
+
+    #[test]
+    fn keeps_formatting_codes_whole() {
+        assert_eq!(unit_len("\x0304,12text"), 6);
+        assert_eq!(unit_len("\x034,5"), 4);
+        assert_eq!(unit_len("\x0304,text"), 3);
+        assert_eq!(unit_len("\x03text"), 1);
+        assert_eq!(unit_len("\x04FF00FF,00FF00x"), 14);
+        assert_eq!(unit_len("\x02bold"), 1);
+
+        // the color code would have been split after "\x030"
+        let text = "aaaaaaaaaa\x0304,12red";
+        assert_eq!(break_point(text, 12), 10);
+        assert_eq!(break_point(text, 16), 16);
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::prelude::*;
+
+        proptest! {
+            // words, multibyte characters, and formatting codes that shouldn't be broken up
+            #[test]
+            fn lines_fit_and_keep_their_text(
+                text in "([a-zé🦀 ]|\x03[0-9]{1,2}(,[0-9]{1,2})?|\x02){1,300}",
+                budget in 20usize..100,
+            ) {
+                let lines = split(&text, budget, &cfg(usize::MAX));
+                for line in &lines {
+                    prop_assert!(line.len() <= budget, "{:?} is over {}", line, budget);
+                }
+                // nothing is lost except the spaces the lines were broken at
+                let joined = lines.concat().replace(" (continued)", "").replace(' ', "");
+                prop_assert_eq!(joined, text.replace(' ', ""));
+            }
+        }
+    }
+
+    #[test]
+    fn budgets_for_our_prefix() {
+        let source = Source::User {
+            nick: "ircrab".to_string(),
+            user: Some("~ircrab".to_string()),
+            host: Some("example.org".to_string()),
+        };
+        let line = format!(":{} PRIVMSG #qux :\r\n", source);
+        assert_eq!(
+            budget(Some(&source), &Command::PRIVMSG, "#qux", 512),
+            512 - line.len()
+        );
+        // a server that takes longer lines fits more
+        assert_eq!(
+            budget(Some(&source), &Command::PRIVMSG, "#qux", 1024),
+            1024 - line.len()
+        );
+        // not knowing our host assumes the worst
+        let unknown = Source::User {
+            nick: "ircrab".to_string(),
+            user: None,
+            host: None,
+        };
+        assert!(budget(Some(&unknown), &Command::PRIVMSG, "#qux", 512) < 512 - line.len());
+        assert!(
+            budget(None, &Command::PRIVMSG, "#qux", 512)
+                < budget(Some(&unknown), &Command::PRIVMSG, "#qux", 512)
+        );
+    }
+}
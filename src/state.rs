@@ -0,0 +1,614 @@
+use crate::casemap::Folded;
+use crate::irc::{Command, Message, Source};
+use crate::isupport::ISupport;
+use crate::mode::{self, ModeChange};
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A channel we're in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Channel {
+    pub name: String,
+    pub topic: Option<Topic>,
+    members: BTreeMap<Folded, Member>,
+    // the members of a NAMES reply that's still coming in, which replace the list once it ends
+    names: Option<BTreeMap<Folded, Member>>,
+}
+
+impl Channel {
+    /// Everyone in the channel, including us
+    pub fn members(&self) -> impl Iterator<Item = &Member> {
+        self.members.values()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Topic {
+    pub text: String,
+    // the nick or mask of whoever set it, or the server
+    pub setter: Option<String>,
+    pub set_at: Option<SystemTime>,
+}
+
+/// Someone in one of our channels
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    pub nick: String,
+    // the status modes they have from PREFIX, like 'o' and 'v', highest first
+    pub modes: Vec<char>,
+}
+
+/// Someone who shares a channel with us, or us
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub realname: Option<String>,
+    pub away: bool,
+}
+
+impl User {
+    fn new(nick: &str) -> User {
+        User {
+            nick: nick.to_string(),
+            user: None,
+            host: None,
+            realname: None,
+            away: false,
+        }
+    }
+
+    // fills in whatever a source includes
+    fn learn(&mut self, source: &Source) {
+        if let Source::User { user, host, .. } = source {
+            self.user = user.clone().or(self.user.take());
+            self.host = host.clone().or(self.host.take());
+        }
+    }
+}
+
+/// What we know of a single connection: the channels we're in, who is in them, who we are,
+/// and what the server supports
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct State {
+    me: String,
+    support: ISupport,
+    // the capabilities the server has acknowledged
+    caps: HashSet<String>,
+    channels: BTreeMap<Folded, Channel>,
+    users: BTreeMap<Folded, User>,
+}
+
+impl State {
+    /// Nothing known yet, ahead of registering as the given nick
+    pub fn new(nick: &str) -> State {
+        State {
+            me: nick.to_string(),
+            support: ISupport::default(),
+            caps: HashSet::new(),
+            channels: BTreeMap::new(),
+            users: BTreeMap::new(),
+        }
+    }
+
+    /// Our current nick
+    pub fn me(&self) -> &str {
+        &self.me
+    }
+
+    /// What the server supports, as of the last RPL_ISUPPORT
+    pub fn support(&self) -> &ISupport {
+        &self.support
+    }
+
+    /// Returns true if the server has acknowledged the capability
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.contains(cap)
+    }
+
+    /// Takes on the capabilities negotiated so far
+    pub fn set_caps(&mut self, caps: &HashSet<String>) {
+        self.caps.clone_from(caps);
+    }
+
+    /// Returns true if the nick is ours
+    pub fn is_me(&self, nick: &str) -> bool {
+        self.support.casemapping.same(nick, &self.me)
+    }
+
+    pub fn channel(&self, name: &str) -> Option<&Channel> {
+        self.channels.get(&self.key(name))
+    }
+
+    // folds every name again, after the server's casemapping changed
+    fn refold(&mut self) {
+        let mapping = self.support.casemapping;
+        let channels = std::mem::take(&mut self.channels);
+        self.channels = channels
+            .into_values()
+            .map(|mut c| {
+                c.members = std::mem::take(&mut c.members)
+                    .into_values()
+                    .map(|m| (mapping.fold(&m.nick), m))
+                    .collect();
+                c.names = None;
+                (mapping.fold(&c.name), c)
+            })
+            .collect();
+        let users = std::mem::take(&mut self.users);
+        self.users = users
+            .into_values()
+            .map(|u| (mapping.fold(&u.nick), u))
+            .collect();
+    }
+
+    /// Update what we know from a message the server sent
+    pub fn update(&mut self, msg: &Message, now: SystemTime) {
+        let p = &msg.params;
+        let nick = msg.source.as_ref().and_then(|s| s.nick());
+        match (&msg.command, nick) {
+            // the server has the final say in what our nick is
+            (Command::RPL_WELCOME, _) if !p.is_empty() => self.me = p[0].clone(),
+            (Command::RPL_ISUPPORT, _) => {
+                let casemapping = self.support.casemapping;
+                self.support.update(msg);
+                if self.support.casemapping != casemapping {
+                    self.refold();
+                }
+            }
+            (Command::JOIN, Some(nick)) if !p.is_empty() => {
+                self.join(&p[0], nick);
+                let user = self.user_mut(nick);
+                if let Some(source) = &msg.source {
+                    user.learn(source);
+                }
+                // extended-join adds the account and realname
+                if let Some(realname) = p.get(2) {
+                    user.realname = Some(realname.clone());
+                }
+            }
+            (Command::PART, Some(nick)) if !p.is_empty() => self.part(&p[0], nick),
+            (Command::KICK, _) if p.len() > 1 => self.part(&p[0], &p[1]),
+            (Command::QUIT, Some(nick)) => self.quit(nick),
+            (Command::NICK, Some(nick)) if !p.is_empty() => self.rename(nick, &p[0]),
+            (Command::MODE, _) if p.len() > 1 => self.mode(msg),
+            (Command::CHGHOST, Some(nick)) if p.len() > 1 => {
+                if let Some(user) = self.users.get_mut(&self.key(nick)) {
+                    user.user = Some(p[0].clone());
+                    user.host = Some(p[1].clone());
+                }
+            }
+            (Command::TOPIC, _) if p.len() > 1 => {
+                let setter = msg.source.as_ref().map(|s| match s {
+                    Source::Server(name) => name.clone(),
+                    Source::User { nick, .. } => nick.clone(),
+                });
+                self.set_topic(&p[0], &p[1], setter, Some(now));
+            }
+            (Command::RPL_NOTOPIC, _) if p.len() > 1 => {
+                if let Some(channel) = self.channel_mut(&p[1]) {
+                    channel.topic = None;
+                }
+            }
+            // RPL_TOPICWHOTIME follows with who set it and when
+            (Command::RPL_TOPIC, _) if p.len() > 2 => self.set_topic(&p[1], &p[2], None, None),
+            (Command::RPL_TOPICWHOTIME, _) if p.len() > 3 => {
+                if let Some(topic) = self.channel_mut(&p[1]).and_then(|c| c.topic.as_mut()) {
+                    topic.setter = Some(p[2].clone());
+                    topic.set_at = p[3]
+                        .parse()
+                        .ok()
+                        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+                }
+            }
+            (Command::RPL_NAMREPLY, _) if p.len() > 3 => self.names(&p[2], &p[3]),
+            (Command::RPL_ENDOFNAMES, _) if p.len() > 1 => {
+                if let Some(channel) = self.channel_mut(&p[1]) {
+                    if let Some(names) = channel.names.take() {
+                        channel.members = names;
+                    }
+                }
+                self.forget_strangers();
+            }
+            (Command::RPL_WHOREPLY, _) if p.len() > 7 => self.who(p),
+            _ => {}
+        }
+    }
+
+    fn key(&self, name: &str) -> Folded {
+        self.support.casemapping.fold(name)
+    }
+
+    // status modes rank by their order in PREFIX, with 0 the highest
+    fn rank(&self, mode: char) -> Option<usize> {
+        self.support.prefix.iter().position(|&(m, _)| m == mode)
+    }
+
+    fn channel_mut(&mut self, name: &str) -> Option<&mut Channel> {
+        let key = self.key(name);
+        self.channels.get_mut(&key)
+    }
+
+    fn user_mut(&mut self, nick: &str) -> &mut User {
+        let key = self.key(nick);
+        self.users.entry(key).or_insert_with(|| User::new(nick))
+    }
+
+    fn join(&mut self, channel: &str, nick: &str) {
+        let key = self.key(channel);
+        if self.is_me(nick) {
+            self.channels.insert(
+                key.clone(),
+                Channel {
+                    name: channel.to_string(),
+                    topic: None,
+                    members: BTreeMap::new(),
+                    names: None,
+                },
+            );
+        }
+        let member = self.key(nick);
+        if let Some(channel) = self.channels.get_mut(&key) {
+            channel.members.insert(
+                member,
+                Member {
+                    nick: nick.to_string(),
+                    modes: vec![],
+                },
+            );
+        }
+    }
+
+    fn part(&mut self, channel: &str, nick: &str) {
+        if self.is_me(nick) {
+            self.channels.remove(&self.key(channel));
+        } else {
+            let key = self.key(nick);
+            if let Some(channel) = self.channel_mut(channel) {
+                channel.members.remove(&key);
+            }
+        }
+        self.forget_strangers();
+    }
+
+    fn quit(&mut self, nick: &str) {
+        let key = self.key(nick);
+        for channel in self.channels.values_mut() {
+            channel.members.remove(&key);
+        }
+        self.forget_strangers();
+    }
+
+    fn rename(&mut self, old: &str, new: &str) {
+        if self.is_me(old) {
+            self.me = new.to_string();
+        }
+        let (old, key) = (self.key(old), self.key(new));
+        if let Some(mut user) = self.users.remove(&old) {
+            user.nick = new.to_string();
+            self.users.insert(key.clone(), user);
+        }
+        for channel in self.channels.values_mut() {
+            if let Some(mut member) = channel.members.remove(&old) {
+                member.nick = new.to_string();
+                channel.members.insert(key.clone(), member);
+            }
+        }
+    }
+
+    fn mode(&mut self, msg: &Message) {
+        let target = self.key(&msg.params[0]);
+        for change in mode::changes(msg, &self.support) {
+            let ModeChange::Status { set, mode, nick } = change else {
+                continue;
+            };
+            let key = self.key(&nick);
+            let (support, channels) = (&self.support, &mut self.channels);
+            let Some(member) = channels
+                .get_mut(&target)
+                .and_then(|c| c.members.get_mut(&key))
+            else {
+                continue;
+            };
+            member.modes.retain(|&m| m != mode);
+            if set {
+                member.modes.push(mode);
+                member
+                    .modes
+                    .sort_by_key(|m| support.prefix.iter().position(|(p, _)| p == m));
+            }
+        }
+    }
+
+    fn set_topic(
+        &mut self,
+        channel: &str,
+        text: &str,
+        setter: Option<String>,
+        set_at: Option<SystemTime>,
+    ) {
+        if let Some(channel) = self.channel_mut(channel) {
+            channel.topic = (!text.is_empty()).then(|| Topic {
+                text: text.to_string(),
+                setter,
+                set_at,
+            });
+        }
+    }
+
+    // a page of NAMES, like "@+alice bob", or "@alice!a@host bob!b@host" with userhost-in-names
+    fn names(&mut self, channel: &str, names: &str) {
+        if self.channel(channel).is_none() {
+            return;
+        }
+        for name in names.split(' ').filter(|n| !n.is_empty()) {
+            let nick = name.trim_start_matches(|c| self.support.prefix.iter().any(|p| p.1 == c));
+            let mut modes: Vec<char> = name[..name.len() - nick.len()]
+                .chars()
+                .filter_map(|c| self.support.prefix.iter().find(|p| p.1 == c).map(|p| p.0))
+                .collect();
+            modes.sort_by_key(|m| self.rank(*m));
+            let source = Source::parse(nick);
+            let Some(nick) = source.nick() else {
+                continue;
+            };
+            self.user_mut(nick).learn(&source);
+            let key = self.key(nick);
+            let member = Member {
+                nick: nick.to_string(),
+                modes,
+            };
+            if let Some(channel) = self.channel_mut(channel) {
+                channel
+                    .names
+                    .get_or_insert_with(BTreeMap::new)
+                    .insert(key, member);
+            }
+        }
+    }
+
+    // <me> <channel> <user> <host> <server> <nick> <flags> :<hopcount> <realname>
+    fn who(&mut self, p: &[String]) {
+        let (channel, nick, flags) = (&p[1], &p[5], &p[6]);
+        if !self.users.contains_key(&self.key(nick)) {
+            return;
+        }
+        let user = self.user_mut(nick);
+        user.user = Some(p[2].clone());
+        user.host = Some(p[3].clone());
+        user.realname = p[7].split_once(' ').map(|(_, r)| r.to_string());
+        // H for here, G for gone, then '*' for an IRC operator and their status prefixes
+        user.away = flags.starts_with('G');
+        let mut modes: Vec<char> = flags
+            .chars()
+            .filter_map(|c| self.support.prefix.iter().find(|p| p.1 == c).map(|p| p.0))
+            .collect();
+        modes.sort_by_key(|m| self.rank(*m));
+        let key = self.key(nick);
+        if let Some(member) = self
+            .channel_mut(channel)
+            .and_then(|c| c.members.get_mut(&key))
+        {
+            member.modes = modes;
+        }
+    }
+
+    // drops everyone who no longer shares a channel with us
+    fn forget_strangers(&mut self) {
+        let me = self.key(&self.me);
+        let channels = &self.channels;
+        self.users.retain(|key, _| {
+            *key == me
+                || channels.values().any(|c| {
+                    c.members.contains_key(key)
+                        || c.names.as_ref().is_some_and(|n| n.contains_key(key))
+                })
+        });
+    }
+}
+
+// what triggers can ask about who is where
+impl State {
+    /// The channels we're in
+    pub fn channels(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.values()
+    }
+
+    /// Someone who shares a channel with us, or us
+    pub fn user(&self, nick: &str) -> Option<&User> {
+        self.users.get(&self.key(nick))
+    }
+
+    /// Someone in one of our channels, with their status there
+    pub fn member(&self, channel: &str, nick: &str) -> Option<&Member> {
+        self.channel(channel)?.members.get(&self.key(nick))
+    }
+
+    /// Returns true if the nick has the given status mode in the channel, or one above it
+    pub fn is_at_least(&self, channel: &str, nick: &str, mode: char) -> bool {
+        let Some(member) = self.member(channel, nick) else {
+            return false;
+        };
+        match self.rank(mode) {
+            Some(rank) => member
+                .modes
+                .iter()
+                .any(|&m| self.rank(m).is_some_and(|r| r <= rank)),
+            None => member.modes.contains(&mode),
+        }
+    }
+
+    /// Returns true if the nick is an operator in the channel, or something higher like an admin
+    pub fn is_op(&self, channel: &str, nick: &str) -> bool {
+        self.is_at_least(channel, nick, 'o')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::casemap::CaseMapping;
+    use crate::irc::parse_message;
+
+    fn state(lines: &[&str]) -> State {
+        let mut state = State::new("ircrab");
+        for line in lines {
+            state.update(&parse_message(line).unwrap(), UNIX_EPOCH);
+        }
+        state
+    }
+
+    fn nicks(state: &State, channel: &str) -> Vec<String> {
+        let mut nicks: Vec<_> = state
+            .channel(channel)
+            .unwrap()
+            .members()
+            .map(|m| m.nick.clone())
+            .collect();
+        nicks.sort();
+        nicks
+    }
+
+    #[test]
+    fn tracks_members() {
+        let s = state(&[
+            ":srv 001 ircrab :Welcome",
+            ":ircrab!~ircrab@example.org JOIN #CWRU",
+            ":srv 353 ircrab = #cwru :ircrab @+alice bob!~bob@example.com",
+            ":srv 353 ircrab = #cwru :+carol",
+            ":srv 366 ircrab #cwru :End of /NAMES list.",
+            ":dave!d@host JOIN #cwru",
+            ":carol!c@host PART #cwru :bye",
+            ":alice!a@host KICK #cwru bob :behave",
+        ]);
+        assert_eq!(s.channel("#cwru").unwrap().name, "#CWRU");
+        assert_eq!(nicks(&s, "#cwru"), vec!["alice", "dave", "ircrab"]);
+        assert_eq!(s.member("#cwru", "ALICE").unwrap().modes, vec!['o', 'v']);
+        assert!(s.is_op("#CWRU", "alice"));
+        assert!(!s.is_op("#cwru", "dave"));
+        assert_eq!(s.user("dave").unwrap().host.as_deref(), Some("host"));
+        // bob and carol left, so there's nothing left to know about them
+        assert!(s.user("bob").is_none());
+        assert!(s.user("carol").is_none());
+        assert_eq!(
+            s.user("ircrab").unwrap().host.as_deref(),
+            Some("example.org")
+        );
+
+        let s = state(&[
+            ":ircrab!u@h JOIN #cwru",
+            ":dave!d@host JOIN #cwru",
+            ":dave!d@host QUIT :gone",
+            ":ircrab!u@h PART #cwru",
+        ]);
+        assert!(s.user("dave").is_none());
+        assert!(s.channel("#cwru").is_none());
+    }
+
+    #[test]
+    fn tracks_nicks() {
+        let s = state(&[
+            ":srv 001 ircrab_ :Welcome",
+            ":ircrab_!u@h JOIN #cwru",
+            ":alice!a@host JOIN #cwru",
+            ":srv MODE #cwru +o alice",
+            ":alice!a@host NICK [Alice]",
+            ":ircrab_!u@h NICK ircrab",
+        ]);
+        assert_eq!(s.me(), "ircrab");
+        assert!(s.is_me("IRCRAB"));
+        assert_eq!(nicks(&s, "#cwru"), vec!["[Alice]", "ircrab"]);
+        assert!(s.is_op("#cwru", "{alice}"));
+        assert!(s.user("alice").is_none());
+    }
+
+    #[test]
+    fn tracks_modes() {
+        let mut s = State::new("ircrab");
+        for line in [
+            "005 ircrab PREFIX=(qaohv)~&@%+ CHANMODES=beI,k,l,imnpst :are supported",
+            ":ircrab!u@h JOIN #cwru",
+            ":srv 353 ircrab = #cwru :ircrab alice bob",
+            ":srv 366 ircrab #cwru :End of /NAMES list.",
+            // the ban mask and limit are skipped, and unsetting a key still takes a parameter
+            ":srv MODE #cwru +bvl-k+qh *!*@spam alice 10 secret alice bob",
+            ":srv MODE #cwru -v+o alice bob",
+        ] {
+            s.update(&parse_message(line).unwrap(), UNIX_EPOCH);
+        }
+        assert_eq!(s.member("#cwru", "alice").unwrap().modes, vec!['q']);
+        assert_eq!(s.member("#cwru", "bob").unwrap().modes, vec!['o', 'h']);
+        assert!(s.is_op("#cwru", "alice"));
+        assert!(s.is_at_least("#cwru", "bob", 'h'));
+        assert!(!s.is_at_least("#cwru", "bob", 'a'));
+        assert!(!s.is_at_least("#cwru", "ircrab", 'v'));
+    }
+
+    #[test]
+    fn tracks_topics() {
+        let s = state(&[
+            ":ircrab!u@h JOIN #cwru",
+            ":srv 332 ircrab #cwru :Welcome to #cwru",
+            ":srv 333 ircrab #cwru alice!a@host 1700000000",
+            ":ircrab!u@h JOIN #rust",
+            ":srv 332 ircrab #rust :old",
+            ":bob!b@host TOPIC #rust :new",
+        ]);
+        assert_eq!(
+            s.channel("#cwru").unwrap().topic,
+            Some(Topic {
+                text: "Welcome to #cwru".to_string(),
+                setter: Some("alice!a@host".to_string()),
+                set_at: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            })
+        );
+        assert_eq!(
+            s.channel("#rust").unwrap().topic,
+            Some(Topic {
+                text: "new".to_string(),
+                setter: Some("bob".to_string()),
+                set_at: Some(UNIX_EPOCH),
+            })
+        );
+
+        let s = state(&[
+            ":ircrab!u@h JOIN #cwru",
+            ":srv 332 ircrab #cwru :Welcome to #cwru",
+            ":bob!b@host TOPIC #cwru :",
+        ]);
+        assert_eq!(s.channel("#cwru").unwrap().topic, None);
+    }
+
+    #[test]
+    fn tracks_who_replies() {
+        let s = state(&[
+            ":ircrab!u@h JOIN #cwru",
+            ":srv 353 ircrab = #cwru :ircrab alice",
+            ":srv 366 ircrab #cwru :End of /NAMES list.",
+            ":srv 352 ircrab #cwru ~alice example.com srv alice G@+ :0 Alice Liddell",
+            ":srv 352 ircrab #other ~mallory example.com srv mallory H :0 Mallory",
+        ]);
+        let alice = s.user("alice").unwrap();
+        assert_eq!(alice.user.as_deref(), Some("~alice"));
+        assert_eq!(alice.host.as_deref(), Some("example.com"));
+        assert_eq!(alice.realname.as_deref(), Some("Alice Liddell"));
+        assert!(alice.away);
+        assert_eq!(s.member("#cwru", "alice").unwrap().modes, vec!['o', 'v']);
+        // we don't share a channel with them, so they aren't worth keeping
+        assert!(s.user("mallory").is_none());
+    }
+
+    #[test]
+    fn refolds_on_casemapping_change() {
+        let mut s = state(&[":ircrab!u@h JOIN #[cwru]", ":[Alice]!a@host JOIN #[cwru]"]);
+        assert!(s.member("#{cwru}", "{alice}").is_some());
+        s.update(
+            &parse_message("005 ircrab CASEMAPPING=ascii :are supported").unwrap(),
+            UNIX_EPOCH,
+        );
+        assert_eq!(s.support().casemapping, CaseMapping::Ascii);
+        assert!(s.member("#{cwru}", "{alice}").is_none());
+        assert!(s.member("#[CWRU]", "[alice]").is_some());
+    }
+}
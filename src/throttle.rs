@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+/// How fast messages may be sent without the server disconnecting us for flooding
+#[derive(Clone, PartialEq, Eq)]
+pub struct FloodControl {
+    // how many messages can be sent back to back after a quiet period
+    pub burst: u32,
+    // how long it takes to earn back the ability to send one more message
+    pub interval: Duration,
+}
+
+/// A token bucket: sending a message costs a token, tokens regenerate one per interval,
+/// and no more than `burst` of them can be saved up
+pub struct Throttle {
+    cfg: FloodControl,
+    tokens: u32,
+    // when the next token will be added, if the bucket isn't full
+    next_refill: Instant,
+}
+
+impl Throttle {
+    pub fn new(cfg: &FloodControl, now: Instant) -> Throttle {
+        Throttle {
+            cfg: cfg.clone(),
+            tokens: cfg.burst,
+            next_refill: now,
+        }
+    }
+
+    /// How long until a message may be sent, which is zero if one may be sent now
+    pub fn wait(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens == 0 {
+            return self.next_refill - now;
+        }
+        Duration::ZERO
+    }
+
+    /// Try to take a token for sending a message. If there isn't one, returns how long
+    /// until there will be.
+    pub fn acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        let wait = self.wait(now);
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        if self.tokens == self.cfg.burst {
+            // a full bucket doesn't regenerate, so start the clock when it's first drawn from
+            self.next_refill = now + self.cfg.interval;
+        }
+        self.tokens -= 1;
+        Ok(())
+    }
+
+    fn refill(&mut self, now: Instant) {
+        while self.tokens < self.cfg.burst && now >= self.next_refill {
+            self.tokens += 1;
+            self.next_refill += self.cfg.interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(start: Instant) -> Throttle {
+        Throttle::new(
+            &FloodControl {
+                burst: 3,
+                interval: Duration::from_secs(2),
+            },
+            start,
+        )
+    }
+
+    #[test]
+    fn allows_a_burst() {
+        let start = Instant::now();
+        let mut t = throttle(start);
+        assert!(t.acquire(start).is_ok());
+        assert!(t.acquire(start).is_ok());
+        assert!(t.acquire(start).is_ok());
+        assert_eq!(t.acquire(start), Err(Duration::from_secs(2)));
+        assert_eq!(
+            t.acquire(start + Duration::from_millis(500)),
+            Err(Duration::from_millis(1500))
+        );
+    }
+
+    #[test]
+    fn waits_without_spending() {
+        let start = Instant::now();
+        let mut t = throttle(start);
+        assert_eq!(t.wait(start), Duration::ZERO);
+        for _ in 0..3 {
+            t.acquire(start).unwrap();
+        }
+        assert_eq!(t.wait(start), Duration::from_secs(2));
+        assert_eq!(t.wait(start + Duration::from_secs(2)), Duration::ZERO);
+        assert_eq!(t.wait(start + Duration::from_secs(2)), Duration::ZERO);
+        assert!(t.acquire(start + Duration::from_secs(2)).is_ok());
+    }
+
+    #[test]
+    fn refills_at_the_interval() {
+        let start = Instant::now();
+        let mut t = throttle(start);
+        for _ in 0..3 {
+            t.acquire(start).unwrap();
+        }
+        assert!(t.acquire(start + Duration::from_secs(2)).is_ok());
+        assert!(t.acquire(start + Duration::from_secs(2)).is_err());
+        assert!(t.acquire(start + Duration::from_secs(4)).is_ok());
+        // a long quiet period only saves up a full burst, not more
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(t.acquire(later).is_ok());
+        }
+        assert_eq!(t.acquire(later), Err(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn steady_rate_after_burst() {
+        let start = Instant::now();
+        let mut t = throttle(start);
+        let mut now = start;
+        let mut sent = vec![];
+        while sent.len() < 6 {
+            match t.acquire(now) {
+                Ok(()) => sent.push(now.duration_since(start).as_secs()),
+                Err(wait) => now += wait,
+            }
+        }
+        assert_eq!(sent, vec![0, 0, 0, 2, 4, 6]);
+    }
+}
@@ -0,0 +1,389 @@
+use ring::digest;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring as provider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default, PartialEq, Eq)]
+pub struct Options {
+    // hex encoded SHA-256 fingerprint of the server's certificate. if set, the certificate is
+    // trusted if and only if it matches, regardless of who issued it
+    pub fingerprint: Option<String>,
+    // skip certificate verification entirely. only for private networks with self-signed certs
+    pub accept_invalid_certs: bool,
+    // PEM file holding the client certificate chain, e.g. for SASL EXTERNAL
+    pub client_cert: Option<PathBuf>,
+    // PEM file holding the client certificate's private key. defaults to `client_cert`
+    pub client_key: Option<PathBuf>,
+}
+
+/// Performs a TLS handshake over the given socket, returning halves that can be used
+/// from separate reader and writer threads
+pub fn connect(mut sock: TcpStream, host: &str, opts: &Options) -> Result<(TlsReader, TlsWriter)> {
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let mut conn =
+        ClientConnection::new(Arc::new(client_config(opts)?), name).map_err(Error::other)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock)?;
+    }
+
+    let conn = Arc::new(Mutex::new(conn));
+    let reader = TlsReader {
+        conn: conn.clone(),
+        sock: sock.try_clone()?,
+    };
+    Ok((reader, TlsWriter { conn, sock }))
+}
+
+fn client_config(opts: &Options) -> Result<ClientConfig> {
+    let builder = ClientConfig::builder_with_provider(Arc::new(provider::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(Error::other)?;
+
+    let builder = if opts.fingerprint.is_some() || opts.accept_invalid_certs {
+        let fingerprint = match &opts.fingerprint {
+            Some(f) => Some(decode_fingerprint(f)?),
+            None => None,
+        };
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PermissiveVerifier { fingerprint }))
+    } else {
+        let mut roots = RootCertStore::empty();
+        let native = rustls_native_certs::load_native_certs();
+        for err in native.errors {
+            warn!(
+                "failed to load a system root certificate: {}. continuing...",
+                err
+            );
+        }
+        roots.add_parsable_certificates(native.certs);
+        builder.with_root_certificates(roots)
+    };
+
+    match &opts.client_cert {
+        Some(cert_path) => {
+            let certs = CertificateDer::pem_file_iter(cert_path)
+                .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+                .map_err(|e| pem_err(cert_path, e))?;
+            let key_path = opts.client_key.as_ref().unwrap_or(cert_path);
+            let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| pem_err(key_path, e))?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(Error::other)
+        }
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn pem_err(path: &Path, e: rustls::pki_types::pem::Error) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("unable to read PEM file {}: {}", path.display(), e),
+    )
+}
+
+// accepts fingerprints with or without colons, in either case
+fn decode_fingerprint(s: &str) -> Result<Vec<u8>> {
+    let hex: String = s.chars().filter(|c| *c != ':').collect();
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("'{}' is not a hex encoded SHA-256 fingerprint", s),
+        )
+    };
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+/// Trusts a server certificate by its fingerprint, or trusts any certificate at all if
+/// there's no fingerprint. Handshake signatures are still verified either way.
+#[derive(Debug)]
+struct PermissiveVerifier {
+    fingerprint: Option<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PermissiveVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if let Some(expected) = &self.fingerprint {
+            let actual = digest::digest(&digest::SHA256, end_entity.as_ref());
+            if actual.as_ref() != expected.as_slice() {
+                return Err(rustls::Error::General(
+                    "server certificate does not match the pinned fingerprint".to_string(),
+                ));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        let algs = provider::default_provider().signature_verification_algorithms;
+        verify_tls12_signature(message, cert, dss, &algs)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        let algs = provider::default_provider().signature_verification_algorithms;
+        verify_tls13_signature(message, cert, dss, &algs)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        provider::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+// The reader and writer share the TLS session, but each has its own handle on the socket.
+// The lock isn't held while waiting to read, so reading doesn't hold up writing. It is held
+// while records are written out, so that they reach the socket in the order they were sealed;
+// a write blocked on a full socket holds up the reader until it completes.
+pub struct TlsReader {
+    conn: Arc<Mutex<ClientConnection>>,
+    sock: TcpStream,
+}
+
+pub struct TlsWriter {
+    conn: Arc<Mutex<ClientConnection>>,
+    sock: TcpStream,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut raw = [0u8; 4096];
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                other => return other,
+            }
+
+            // no plaintext is buffered, so wait on more from the socket
+            let n = self.sock.read(&mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut conn = self.conn.lock().unwrap();
+            let mut records = &raw[..n];
+            while !records.is_empty() {
+                conn.read_tls(&mut records)?;
+                conn.process_new_packets().map_err(Error::other)?;
+            }
+            // e.g. alerts or key updates
+            while conn.wants_write() {
+                conn.write_tls(&mut self.sock)?;
+            }
+        }
+    }
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let n = conn.writer().write(buf)?;
+        while conn.wants_write() {
+            conn.write_tls(&mut self.sock)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.writer().flush()?;
+        while conn.wants_write() {
+            conn.write_tls(&mut self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{ServerConfig, ServerConnection, StreamOwned};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn server_cert() -> CertifiedKey {
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap()
+    }
+
+    fn fingerprint(cert: &CertifiedKey) -> String {
+        digest::digest(&digest::SHA256, cert.cert.der())
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    // accepts a single connection, sends a line, and returns the line sent back
+    fn serve(config: ServerConfig) -> (u16, thread::JoinHandle<Option<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let conn = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut stream = StreamOwned::new(conn, sock);
+            stream.write_all(b"PING :localhost\r\n").ok()?;
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).ok()?;
+            Some(line)
+        });
+        (port, handle)
+    }
+
+    fn server_config() -> rustls::ConfigBuilder<ServerConfig, rustls::WantsVerifier> {
+        ServerConfig::builder_with_provider(Arc::new(provider::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+    }
+
+    fn plain_server(cert: &CertifiedKey) -> ServerConfig {
+        server_config()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.cert.der().clone()],
+                PrivateKeyDer::try_from(cert.key_pair.serialize_der()).unwrap(),
+            )
+            .unwrap()
+    }
+
+    fn round_trip(port: u16, opts: &Options) -> Result<String> {
+        let sock = TcpStream::connect(("127.0.0.1", port))?;
+        let (reader, mut writer) = connect(sock, "localhost", opts)?;
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line)?;
+        writer.write_all(b"PONG :localhost\r\n")?;
+        writer.flush()?;
+        Ok(line)
+    }
+
+    #[test]
+    fn connects_with_pinned_fingerprint() {
+        let cert = server_cert();
+        let (port, server) = serve(plain_server(&cert));
+        let opts = Options {
+            fingerprint: Some(fingerprint(&cert)),
+            ..Default::default()
+        };
+        assert_eq!(round_trip(port, &opts).unwrap(), "PING :localhost\r\n");
+        assert_eq!(server.join().unwrap().unwrap(), "PONG :localhost\r\n");
+    }
+
+    #[test]
+    fn rejects_mismatched_fingerprint() {
+        let cert = server_cert();
+        let (port, _) = serve(plain_server(&cert));
+        let opts = Options {
+            fingerprint: Some(fingerprint(&server_cert())),
+            ..Default::default()
+        };
+        assert!(round_trip(port, &opts).is_err());
+    }
+
+    #[test]
+    fn rejects_self_signed_by_default() {
+        let cert = server_cert();
+        let (port, _) = serve(plain_server(&cert));
+        assert!(round_trip(port, &Options::default()).is_err());
+    }
+
+    #[test]
+    fn accepts_self_signed_when_asked() {
+        let cert = server_cert();
+        let (port, server) = serve(plain_server(&cert));
+        let opts = Options {
+            accept_invalid_certs: true,
+            ..Default::default()
+        };
+        assert_eq!(round_trip(port, &opts).unwrap(), "PING :localhost\r\n");
+        assert_eq!(server.join().unwrap().unwrap(), "PONG :localhost\r\n");
+    }
+
+    #[test]
+    fn presents_client_certificate() {
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let client_key = KeyPair::generate().unwrap();
+        let client = CertificateParams::new(vec!["ircrab".to_string()])
+            .unwrap()
+            .signed_by(&client_key, &ca, &ca_key)
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("ircrab-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("client.pem");
+        std::fs::write(&cert_path, client.pem() + &client_key.serialize_pem()).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let verifier = WebPkiClientVerifier::builder_with_provider(
+            Arc::new(roots),
+            Arc::new(provider::default_provider()),
+        )
+        .build()
+        .unwrap();
+        let cert = server_cert();
+        let config = server_config()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                vec![cert.cert.der().clone()],
+                PrivateKeyDer::try_from(cert.key_pair.serialize_der()).unwrap(),
+            )
+            .unwrap();
+        let (port, server) = serve(config);
+
+        let opts = Options {
+            accept_invalid_certs: true,
+            client_cert: Some(cert_path),
+            ..Default::default()
+        };
+        assert_eq!(round_trip(port, &opts).unwrap(), "PING :localhost\r\n");
+        assert_eq!(server.join().unwrap().unwrap(), "PONG :localhost\r\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn decodes_fingerprints() {
+        let hex = "AB".repeat(32);
+        assert_eq!(decode_fingerprint(&hex).unwrap(), vec![0xab; 32]);
+        let colons = vec!["ab"; 32].join(":");
+        assert_eq!(decode_fingerprint(&colons).unwrap(), vec![0xab; 32]);
+        assert!(decode_fingerprint("abcd").is_err());
+        assert!(decode_fingerprint(&"zz".repeat(32)).is_err());
+    }
+}
@@ -1,30 +1,136 @@
 use super::irc;
+use super::queue::Sender;
+use super::state::State;
+use super::Config;
 use once_cell::sync::Lazy;
-use std::sync::mpsc::Sender;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
 pub mod heartbeat;
+pub mod modes;
 pub mod on_connect;
 pub mod ping;
+pub mod reload;
+
+/// Which triggers run, and how
+#[derive(Clone, Default)]
+pub struct Settings {
+    pub heartbeat: Heartbeat,
+    pub on_connect: OnConnect,
+    pub reload: Reload,
+    pub modes: Modes,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub enabled: bool,
+    // the message that's answered, and the answer
+    pub command: String,
+    pub reply: String,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Heartbeat {
+        Heartbeat {
+            enabled: true,
+            command: "!ping".to_string(),
+            reply: "pong!".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct OnConnect {
+    pub enabled: bool,
+    // how long to wait after registering before joining channels
+    pub delay: Duration,
+}
+
+impl Default for OnConnect {
+    fn default() -> OnConnect {
+        OnConnect {
+            enabled: true,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Reload {
+    pub enabled: bool,
+    // the private message that reloads the config
+    pub command: String,
+    // the services accounts allowed to send it
+    pub accounts: Vec<String>,
+    // the channels whose operators are allowed to send it too
+    pub channels: Vec<String>,
+}
+
+impl Default for Reload {
+    fn default() -> Reload {
+        Reload {
+            enabled: true,
+            command: "!reload".to_string(),
+            accounts: vec![],
+            channels: vec![],
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Modes {
+    pub enabled: bool,
+    // the services accounts allowed to have the bot set modes in any channel. operators
+    // can have it set modes in their own channels
+    pub accounts: Vec<String>,
+}
+
+impl Default for Modes {
+    fn default() -> Modes {
+        Modes {
+            enabled: true,
+            accounts: vec![],
+        }
+    }
+}
+
+/// Everything a trigger can look at, and the way to send replies
+pub struct Context<'a> {
+    pub config: &'a Config,
+    // the channels we're in, who is in them, our nick, and what the server supports
+    pub state: &'a State,
+    pub tx: &'a Sender,
+    // when the message arrived
+    pub now: Instant,
+    // set to have the config reloaded once the message is handled, with who to tell about it
+    pub reload: &'a Cell<Option<String>>,
+}
 
 pub trait Trigger {
-    fn condition(&self, _: &Sender<irc::Message>, _: &irc::Message) -> bool;
-    fn action(&self, _: &Sender<irc::Message>, _: &irc::Message) -> bool;
+    fn condition(&self, _: &Context, _: &irc::MessageRef) -> bool;
+    fn action(&self, _: &Context, _: &irc::Message) -> bool;
 }
 
+// TODO(raidancampbell): why was +send +sync needed here?
+pub type TriggerFn = Box<dyn Fn(&Context, &irc::Message) -> bool + Send + Sync>;
+// conditions are checked against every line received, so they look at it where it was read
+// rather than at a copy
+pub type ConditionFn = Box<dyn Fn(&Context, &irc::MessageRef) -> bool + Send + Sync>;
+
 pub struct SyncTrigger {
     // Returns true if this trigger applies to the passed in message
-    pub cond: Lazy<Box<dyn Fn(&Sender<irc::Message>, &irc::Message) -> bool + Send + Sync>>, // TODO(raidancampbell): why was +send +sync needed here?
+    pub cond: Lazy<ConditionFn>,
 
     // The action to perform if cond is true
     // return true if processing should continue
-    pub act: Lazy<Box<dyn Fn(&Sender<irc::Message>, &irc::Message) -> bool + Send + Sync>>,
+    pub act: Lazy<TriggerFn>,
 }
 
 impl Trigger for SyncTrigger {
-    fn condition(&self, tx: &Sender<irc::Message>, msg: &irc::Message) -> bool {
-        (self.cond)(tx, msg)
+    fn condition(&self, ctx: &Context, msg: &irc::MessageRef) -> bool {
+        (self.cond)(ctx, msg)
     }
 
-    fn action(&self, tx: &Sender<irc::Message>, msg: &irc::Message) -> bool {
-        (self.act)(tx, msg)
+    fn action(&self, ctx: &Context, msg: &irc::Message) -> bool {
+        (self.act)(ctx, msg)
     }
 }
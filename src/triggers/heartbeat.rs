@@ -1,23 +1,29 @@
-use super::SyncTrigger;
+use super::{Context, SyncTrigger};
 use crate::irc;
 use crate::irc::Command;
 use once_cell::sync::Lazy;
-use std::sync::mpsc::Sender;
 
 // TODO(raidancampbell): can Lazy be removed here and still retain this single instance usage?
 pub static HEARTBEAT: SyncTrigger = SyncTrigger {
     cond: Lazy::new(|| {
-        Box::new(|_: &Sender<irc::Message>, msg: &irc::Message| {
-            msg.command == Command::PRIVMSG && msg.params.len() > 1 && msg.params[1] == *"!ping"
+        Box::new(|ctx: &Context, msg: &irc::MessageRef| {
+            msg.command == Command::PRIVMSG
+                && msg.params().nth(1) == Some(ctx.config.triggers.heartbeat.command.as_str())
         })
     }),
     act: Lazy::new(|| {
-        Box::new(|tx: &Sender<irc::Message>, msg: &irc::Message| {
-            let resp = irc::Message {
-                command: Command::PRIVMSG,
-                params: vec![msg.params[0].clone(), "pong!".to_string()],
+        Box::new(|ctx: &Context, msg: &irc::Message| {
+            // a private message is addressed to us, so the reply goes back to the sender
+            let target = match msg.source.as_ref().and_then(|s| s.nick()) {
+                Some(nick) if !ctx.state.support().is_channel(&msg.params[0]) => nick.to_string(),
+                _ => msg.params[0].clone(),
             };
-            tx.send(resp).unwrap();
+            let reply = ctx.config.triggers.heartbeat.reply.clone();
+            let mut resp = irc::Message::new(Command::PRIVMSG, vec![target, reply]);
+            if let Some(msgid) = msg.tags.get("msgid") {
+                resp = resp.with_tag("+reply", msgid);
+            }
+            ctx.tx.send(resp).unwrap();
             false
         })
     }),
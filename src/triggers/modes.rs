@@ -0,0 +1,322 @@
+use super::{Context, SyncTrigger};
+use crate::irc;
+use crate::irc::Command;
+use crate::mode::Builder;
+use crate::state::State;
+use once_cell::sync::Lazy;
+
+// adds a change for one nick or mask
+type Add = fn(Builder, &str) -> Builder;
+
+// one of the commands, and the change it makes
+struct Action {
+    name: &'static str,
+    add: Add,
+    // whether the status mode is set and which it is, or None for a ban
+    status: Option<(bool, char)>,
+}
+
+const ACTIONS: [Action; 6] = [
+    Action {
+        name: "!op",
+        add: Builder::op,
+        status: Some((true, 'o')),
+    },
+    Action {
+        name: "!deop",
+        add: Builder::deop,
+        status: Some((false, 'o')),
+    },
+    Action {
+        name: "!voice",
+        add: Builder::voice,
+        status: Some((true, 'v')),
+    },
+    Action {
+        name: "!devoice",
+        add: Builder::devoice,
+        status: Some((false, 'v')),
+    },
+    Action {
+        name: "!ban",
+        add: Builder::ban,
+        status: None,
+    },
+    Action {
+        name: "!unban",
+        add: Builder::unban,
+        status: None,
+    },
+];
+
+/// Who asked for modes to be set
+pub struct Requester<'a> {
+    pub nick: &'a str,
+    // logged in to one of the accounts in the settings, so allowed in every channel.
+    // anyone else is only allowed in the channels they're an operator in
+    pub admin: bool,
+}
+
+pub static MODES: SyncTrigger = SyncTrigger {
+    // private messages like "!op #cwru alice bob". who may ask for what is up to the request
+    cond: Lazy::new(|| {
+        Box::new(|ctx: &Context, msg: &irc::MessageRef| {
+            let mut params = msg.params();
+            msg.command == Command::PRIVMSG
+                && params.next().is_some_and(|target| ctx.state.is_me(target))
+                && params.next().is_some_and(|text| action(text).is_some())
+        })
+    }),
+    act: Lazy::new(|| {
+        Box::new(|ctx: &Context, msg: &irc::Message| {
+            let settings = &ctx.config.triggers.modes;
+            let from = Requester {
+                nick: msg
+                    .source
+                    .as_ref()
+                    .and_then(|s| s.nick())
+                    .unwrap_or_default(),
+                admin: msg
+                    .tags
+                    .get("account")
+                    .is_some_and(|account| settings.accounts.iter().any(|a| a == account)),
+            };
+            match request(&msg.params[1], &from, ctx.state) {
+                Ok(lines) => lines.into_iter().for_each(|m| ctx.tx.send(m).unwrap()),
+                Err(e) => {
+                    let notice = irc::Message::new(Command::NOTICE, vec![from.nick.to_string(), e]);
+                    ctx.tx.send(notice).unwrap();
+                }
+            }
+            false
+        })
+    }),
+};
+
+fn action(text: &str) -> Option<&'static Action> {
+    let word = text.split(' ').next()?;
+    ACTIONS.iter().find(|a| a.name.eq_ignore_ascii_case(word))
+}
+
+/// The MODE lines for a request like `!op #cwru alice bob`, or why it can't be sent.
+/// Without a channel, like `!op alice`, the request is for every channel it can be done in.
+/// `*` stands for everyone in the channel but us.
+pub fn request(text: &str, from: &Requester, state: &State) -> Result<Vec<irc::Message>, String> {
+    let mut words = text.split(' ').filter(|w| !w.is_empty()).peekable();
+    let name = words.next().unwrap_or_default();
+    let action = action(name).ok_or_else(|| format!("`{}` isn't a mode command", name))?;
+    let channel = words.next_if(|w| state.support().is_channel(w));
+    let targets: Vec<&str> = words.collect();
+    if targets.is_empty() {
+        return Err(format!("usage: {} [channel] <nick or mask>...", name));
+    }
+    if let Some(channel) = channel {
+        return changes(action, channel, &targets, from, state, true);
+    }
+    let lines: Vec<_> = state
+        .channels()
+        .filter_map(|c| changes(action, &c.name, &targets, from, state, false).ok())
+        .flatten()
+        .collect();
+    if lines.is_empty() {
+        return Err(format!("there's nowhere I can {}", text.trim()));
+    }
+    Ok(lines)
+}
+
+// the changes to make in one channel. if it was named, nicks that aren't in it are an error,
+// otherwise they're left out
+fn changes(
+    action: &Action,
+    channel: &str,
+    targets: &[&str],
+    from: &Requester,
+    state: &State,
+    named: bool,
+) -> Result<Vec<irc::Message>, String> {
+    let Some(chan) = state.channel(channel) else {
+        return Err(format!("I'm not in {}", channel));
+    };
+    if !from.admin && !state.is_op(channel, from.nick) {
+        return Err(format!("only operators in {} can do that", chan.name));
+    }
+    if !state.is_op(channel, state.me()) {
+        return Err(format!("I'm not an operator in {}", chan.name));
+    }
+    let mut builder = Builder::new(&chan.name);
+    for &target in targets {
+        builder = match action.status {
+            // everyone who'd be changed by it, except us
+            Some((set, mode)) if target == "*" => chan
+                .members()
+                .filter(|m| !state.is_me(&m.nick) && m.modes.contains(&mode) != set)
+                .fold(builder, |b, m| (action.add)(b, &m.nick)),
+            Some(_) => match state.member(channel, target) {
+                Some(member) => (action.add)(builder, &member.nick),
+                None if named => return Err(format!("{} isn't in {}", target, chan.name)),
+                None => builder,
+            },
+            None => (action.add)(builder, &ban_mask(target, state)),
+        };
+    }
+    Ok(builder.build(state.support()))
+}
+
+// a mask is taken as it is. a nick is banned by their host, if we know it
+fn ban_mask(target: &str, state: &State) -> String {
+    if target.contains(['!', '@', '*', '?']) {
+        return target.to_string();
+    }
+    match state.user(target).and_then(|u| u.host.as_deref()) {
+        Some(host) => format!("*!*@{}", host),
+        None => format!("{}!*@*", target),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::parse_message;
+    use std::time::UNIX_EPOCH;
+
+    const ADMIN: Requester = Requester {
+        nick: "admin",
+        admin: true,
+    };
+
+    fn state(lines: &[&str]) -> State {
+        let mut state = State::new("ircrab");
+        for line in lines {
+            state.update(&parse_message(line).unwrap(), UNIX_EPOCH);
+        }
+        state
+    }
+
+    // we're an op in #cwru, alongside alice, bob with voice, and carol
+    fn cwru(extra: &[&str]) -> State {
+        let mut lines = vec![
+            ":ircrab!u@h JOIN #cwru",
+            ":srv 353 ircrab = #cwru :@ircrab alice!a@alice.example.org +bob carol",
+            ":srv 366 ircrab #cwru :End of /NAMES list.",
+        ];
+        lines.extend(extra);
+        state(&lines)
+    }
+
+    fn lines(text: &str, from: &Requester, state: &State) -> Result<Vec<String>, String> {
+        request(text, from, state).map(|lines| {
+            lines
+                .iter()
+                .map(|m| m.serialize().unwrap().trim_end().to_string())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn batches_requests_by_modes() {
+        let s = cwru(&["005 ircrab MODES=2 :are supported"]);
+        assert_eq!(
+            lines("!op #cwru alice BOB carol", &ADMIN, &s),
+            Ok(vec![
+                "MODE #cwru +oo alice bob".to_string(),
+                "MODE #cwru +o carol".to_string()
+            ])
+        );
+        assert_eq!(
+            lines("!UNBAN  #CWRU *!*@spam", &ADMIN, &s),
+            Ok(vec!["MODE #cwru -b *!*@spam".to_string()])
+        );
+    }
+
+    #[test]
+    fn fills_in_nicks() {
+        let s = cwru(&[]);
+        assert_eq!(
+            lines("!voice #cwru *", &ADMIN, &s),
+            Ok(vec!["MODE #cwru +vv alice carol".to_string()])
+        );
+        assert_eq!(
+            lines("!devoice #cwru *", &ADMIN, &s),
+            Ok(vec!["MODE #cwru -v bob".to_string()])
+        );
+        // by their host when it's known
+        assert_eq!(
+            lines("!ban #cwru alice carol", &ADMIN, &s),
+            Ok(vec![
+                "MODE #cwru +bb *!*@alice.example.org carol!*@*".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn goes_everywhere_without_a_channel() {
+        let s = cwru(&[
+            ":ircrab!u@h JOIN #rust",
+            ":srv 353 ircrab = #rust :@ircrab alice",
+            ":srv 366 ircrab #rust :End of /NAMES list.",
+            ":ircrab!u@h JOIN #quiet",
+            ":alice!a@host JOIN #quiet",
+        ]);
+        // we aren't an op in #quiet, and bob is only in #cwru
+        assert_eq!(
+            lines("!op alice bob", &ADMIN, &s),
+            Ok(vec![
+                "MODE #cwru +oo alice bob".to_string(),
+                "MODE #rust +o alice".to_string()
+            ])
+        );
+        assert_eq!(
+            lines("!op dave", &ADMIN, &s),
+            Err("there's nowhere I can !op dave".to_string())
+        );
+    }
+
+    #[test]
+    fn lets_operators_ask_in_their_channels() {
+        let s = cwru(&[
+            ":srv MODE #cwru +o alice",
+            ":ircrab!u@h JOIN #rust",
+            ":srv 353 ircrab = #rust :@ircrab alice bob",
+            ":srv 366 ircrab #rust :End of /NAMES list.",
+        ]);
+        let alice = Requester {
+            nick: "alice",
+            admin: false,
+        };
+        assert_eq!(
+            lines("!voice #cwru carol", &alice, &s),
+            Ok(vec!["MODE #cwru +v carol".to_string()])
+        );
+        assert_eq!(
+            lines("!voice #rust bob", &alice, &s),
+            Err("only operators in #rust can do that".to_string())
+        );
+        assert_eq!(
+            lines("!voice bob", &alice, &s),
+            Ok(vec!["MODE #cwru +v bob".to_string()])
+        );
+    }
+
+    #[test]
+    fn turns_away_bad_requests() {
+        let s = cwru(&[":ircrab!u@h JOIN #quiet"]);
+        assert_eq!(
+            lines("!voice #cwru", &ADMIN, &s),
+            Err("usage: !voice [channel] <nick or mask>...".to_string())
+        );
+        assert_eq!(
+            lines("!voice #rust alice", &ADMIN, &s),
+            Err("I'm not in #rust".to_string())
+        );
+        assert_eq!(
+            lines("!voice #quiet ircrab", &ADMIN, &s),
+            Err("I'm not an operator in #quiet".to_string())
+        );
+        assert_eq!(
+            lines("!voice #cwru dave", &ADMIN, &s),
+            Err("dave isn't in #cwru".to_string())
+        );
+        assert!(action("!opme").is_none());
+        assert!(action("hi !op").is_none());
+    }
+}
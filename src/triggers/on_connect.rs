@@ -1,29 +1,165 @@
-use super::SyncTrigger;
+use super::{Context, SyncTrigger};
 use crate::irc;
 use crate::irc::Command;
+use crate::isupport::ISupport;
+use crate::Channel;
 use once_cell::sync::Lazy;
-use std::sync::mpsc::Sender;
-use std::{process, thread, time};
+use std::{thread, time::Instant};
+
+// what's left of a line around the channels and keys in "JOIN <channels> <keys>\r\n"
+const JOIN_OVERHEAD: usize = "JOIN \r\n".len();
 
 pub static ON_CONNECT: SyncTrigger = SyncTrigger {
+    // ISUPPORT has all arrived by the end of the MOTD, so TARGMAX is known
     cond: Lazy::new(|| {
-        Box::new(|_: &Sender<irc::Message>, msg: &irc::Message| msg.command == Command::RPL_WELCOME)
+        Box::new(|_: &Context, msg: &irc::MessageRef| {
+            msg.command == Command::RPL_ENDOFMOTD || msg.command == Command::ERR_NOMOTD
+        })
     }),
     act: Lazy::new(|| {
-        Box::new(|tx: &Sender<irc::Message>, _: &irc::Message| {
-            thread::sleep(time::Duration::from_millis(1000));
-            let resp = irc::Message {
-                command: Command::JOIN,
-                // TODO(raidancampbell): find a way to access the configuration
-                //  from here instead of hardcoding
-                params: vec!["#cwru".to_string()],
-            };
-            tx.send(resp).unwrap_or_else(|err| {
-                eprintln!("Problem sending onConnect command: {err}");
-                process::exit(1);
+        Box::new(|ctx: &Context, _: &irc::Message| {
+            let joins = joins(&ctx.config.network.channels, ctx.state.support());
+            if joins.is_empty() {
+                return false;
+            }
+            let tx = ctx.tx.clone();
+            let at = ctx.now + ctx.config.triggers.on_connect.delay;
+            thread::spawn(move || {
+                thread::sleep(at.saturating_duration_since(Instant::now()));
+                for join in joins {
+                    // the connection went away while we waited, and will autojoin again
+                    if tx.send(join).is_err() {
+                        break;
+                    }
+                }
             });
-
             false
         })
     }),
 };
+
+/// The JOIN lines for the given channels, with as many channels in each as TARGMAX and the
+/// line length allow. Keyed channels go first, since a line's keys apply to its leading channels.
+/// Names the server doesn't take for channels, by CHANTYPES, are left out.
+pub fn joins(channels: &[Channel], support: &ISupport) -> Vec<irc::Message> {
+    let max_targets = support.max_targets("JOIN").unwrap_or(usize::MAX).max(1);
+    let budget = support.linelen.saturating_sub(JOIN_OVERHEAD);
+    let (keyed, open): (Vec<_>, Vec<_>) = channels
+        .iter()
+        .filter(|c| {
+            let ok = support.is_channel(&c.name);
+            if !ok {
+                warn!(
+                    "Not joining {}, which isn't a channel on this server",
+                    c.name
+                );
+            }
+            ok
+        })
+        .partition(|c| c.key.is_some());
+    let mut lines = vec![];
+    let mut names: Vec<&str> = vec![];
+    let mut keys: Vec<&str> = vec![];
+    let mut len = 0;
+    for channel in keyed.into_iter().chain(open) {
+        let key = channel.key.as_deref();
+        // counting the comma or space before each channel and key
+        let added = 1 + channel.name.len() + key.map_or(0, |k| 1 + k.len());
+        if !names.is_empty() && (names.len() >= max_targets || len + added > budget) {
+            lines.push(join(&mut names, &mut keys));
+            len = 0;
+        }
+        names.push(&channel.name);
+        keys.extend(key);
+        len += added;
+    }
+    if !names.is_empty() {
+        lines.push(join(&mut names, &mut keys));
+    }
+    lines
+}
+
+fn join(names: &mut Vec<&str>, keys: &mut Vec<&str>) -> irc::Message {
+    let mut params = vec![names.join(",")];
+    if !keys.is_empty() {
+        params.push(keys.join(","));
+    }
+    names.clear();
+    keys.clear();
+    irc::Message::new(Command::JOIN, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::parse_message;
+
+    fn channel(name: &str, key: Option<&str>) -> Channel {
+        Channel {
+            name: name.to_string(),
+            key: key.map(str::to_string),
+        }
+    }
+
+    fn lines(channels: &[Channel], support: &ISupport) -> Vec<String> {
+        joins(channels, support)
+            .iter()
+            .map(|m| m.serialize_within(support.linelen).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn puts_keyed_channels_first() {
+        let channels = [
+            channel("#open", None),
+            channel("#secret", Some("hunter2")),
+            channel("#other", None),
+            channel("#vault", Some("s3same")),
+        ];
+        assert_eq!(
+            lines(&channels, &ISupport::default()),
+            vec!["JOIN #secret,#vault,#open,#other hunter2,s3same\r\n"]
+        );
+        assert!(lines(&[], &ISupport::default()).is_empty());
+    }
+
+    #[test]
+    fn batches_by_targmax_and_length() {
+        let mut support = ISupport::default();
+        support
+            .update(&parse_message("005 ircrab TARGMAX=JOIN:2,PRIVMSG:4 :are supported").unwrap());
+        let channels = [
+            channel("#a", Some("k")),
+            channel("#b", None),
+            channel("#c", None),
+        ];
+        assert_eq!(
+            lines(&channels, &support),
+            vec!["JOIN #a,#b k\r\n", "JOIN #c\r\n"]
+        );
+
+        let channels: Vec<_> = (0..20)
+            .map(|i| channel(&format!("#{}{}", i, "x".repeat(60)), None))
+            .collect();
+        let sent = lines(&channels, &ISupport::default());
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|l| l.len() <= 512));
+        assert_eq!(sent.concat().matches('#').count(), 20);
+
+        // a server that takes longer lines needs fewer of them
+        support.update(&parse_message("005 ircrab TARGMAX= LINELEN=2048 :are supported").unwrap());
+        assert_eq!(lines(&channels, &support).len(), 1);
+    }
+
+    #[test]
+    fn skips_what_the_server_does_not_call_a_channel() {
+        let mut support = ISupport::default();
+        support.update(&parse_message("005 ircrab CHANTYPES=# :are supported").unwrap());
+        let channels = [channel("&local", None), channel("#cwru", None)];
+        assert_eq!(lines(&channels, &support), vec!["JOIN #cwru\r\n"]);
+        assert_eq!(
+            lines(&channels, &ISupport::default()),
+            vec!["JOIN &local,#cwru\r\n"]
+        );
+    }
+}
@@ -1,20 +1,14 @@
-use super::SyncTrigger;
+use super::{Context, SyncTrigger};
 use crate::irc;
 use crate::irc::Command;
 use once_cell::sync::Lazy;
-use std::sync::mpsc::Sender;
 
 pub static PING: SyncTrigger = SyncTrigger {
-    cond: Lazy::new(|| {
-        Box::new(|_: &Sender<irc::Message>, msg: &irc::Message| msg.command == Command::PING)
-    }),
+    cond: Lazy::new(|| Box::new(|_: &Context, msg: &irc::MessageRef| msg.command == Command::PING)),
     act: Lazy::new(|| {
-        Box::new(|tx: &Sender<irc::Message>, msg: &irc::Message| {
-            let resp = irc::Message {
-                command: Command::PONG,
-                params: msg.params.clone(),
-            };
-            tx.send(resp).unwrap();
+        Box::new(|ctx: &Context, msg: &irc::Message| {
+            let resp = irc::Message::new(Command::PONG, msg.params.clone());
+            ctx.tx.send(resp).unwrap();
             false
         })
     }),
@@ -0,0 +1,37 @@
+use super::{Context, SyncTrigger};
+use crate::irc;
+use crate::irc::Command;
+use once_cell::sync::Lazy;
+
+pub static RELOAD: SyncTrigger = SyncTrigger {
+    // only private messages, from someone logged in to an admin account or an operator in one
+    // of the admin channels. the account tag comes from services, so unlike a nick or a host it
+    // can't be taken over
+    cond: Lazy::new(|| {
+        Box::new(|ctx: &Context, msg: &irc::MessageRef| {
+            let settings = &ctx.config.triggers.reload;
+            let mut params = msg.params();
+            msg.command == Command::PRIVMSG
+                && params.next().is_some_and(|target| ctx.state.is_me(target))
+                && params.next() == Some(settings.command.as_str())
+                && (msg
+                    .tag("account")
+                    .is_some_and(|account| settings.accounts.iter().any(|a| *a == account))
+                    || msg.nick().is_some_and(|nick| {
+                        settings.channels.iter().any(|c| ctx.state.is_op(c, nick))
+                    }))
+        })
+    }),
+    act: Lazy::new(|| {
+        Box::new(|ctx: &Context, msg: &irc::Message| {
+            let nick = msg
+                .source
+                .as_ref()
+                .and_then(|s| s.nick())
+                .unwrap_or_default();
+            info!("Reload requested by {}", nick);
+            ctx.reload.set(Some(nick.to_string()));
+            false
+        })
+    }),
+};